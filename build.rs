@@ -11,4 +11,9 @@ fn main() {
 
     fs::copy("memory-cb.x", out_dir.join("memory-cb.x")).unwrap();
     println!("cargo:rerun-if-changed=memory-cb.x");
+
+    // defmt needs its own linker script to lay out the log strings
+    if env::var_os("CARGO_FEATURE_DEFMT_LOG").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
 }
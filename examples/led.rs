@@ -0,0 +1,78 @@
+// Shows how to run code of your own at the same point in every frame,
+// between moving the fish and drawing them, for keeping something else
+// in time with the picture.  Here the "LED" is only printed.  It
+// toggles every frame, and the events from the frame, such as fish
+// opening their mouths, are printed alongside it.
+//
+//   cargo run --example led --features std \
+//       --target x86_64-unknown-linux-gnu
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_graphics::drawable::Pixel;
+use embedded_graphics::geometry::Size;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::DrawTarget;
+use rs_embedded_fish::FishTank;
+
+// The sprite data is read as u16 words, so it has to be 2-byte aligned,
+// which include_bytes! doesn't promise on its own.
+#[repr(align(2))]
+struct Aligned<T: ?Sized>(T);
+
+static SPRITE_DATA: &Aligned<[u8]> =
+    &Aligned(*include_bytes!("../src/fish.raw"));
+
+// Same size as the Longan Nano's LCD.
+const WIDTH: u32 = 160;
+const HEIGHT: u32 = 80;
+
+// Same as the Longan Nano program's TARGET_FPS.
+const FRAME_TIME: Duration = Duration::from_millis(50);
+
+const FRAMES: u32 = 100;
+
+// A screen which throws away what is drawn on it.
+struct NullDisplay;
+
+impl DrawTarget<Rgb565> for NullDisplay {
+    type Error = ();
+
+    fn draw_pixel(&mut self, _pixel: Pixel<Rgb565>) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn size(&self) -> Size {
+        Size::new(WIDTH, HEIGHT)
+    }
+}
+
+fn main() {
+    let mut display = NullDisplay;
+    let mut fish_tank = FishTank::new(display.size(), &SPRITE_DATA.0,
+                                      0x1badd00d8badf00d).unwrap();
+    fish_tank.render_all(&mut display).unwrap();
+
+    let mut led = false;
+    // Each frame is timed from when the previous one was due, rather
+    // than from when it finished, so that slow frames don't make the
+    // LED drift behind.
+    let mut deadline = Instant::now();
+    for frame in 0..FRAMES {
+        fish_tank.step_and_render(&mut display, |tank| {
+            led = !led;
+            let state = if led { "on" } else { "off" };
+            print!("frame {:3}: LED {}", frame, state);
+            for event in tank.events() {
+                print!("  {:?}", event);
+            }
+            println!();
+        }).unwrap();
+
+        deadline += FRAME_TIME;
+        if let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+}
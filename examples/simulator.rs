@@ -0,0 +1,60 @@
+// Runs the fish tank in a window on the host, so it can be tried out
+// without a Longan Nano.
+//
+//   cargo run --example simulator --features simulator \
+//       --target x86_64-unknown-linux-gnu
+
+use std::thread;
+use std::time::Duration;
+
+use embedded_graphics::geometry::Size;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::DrawTarget;
+use embedded_graphics_simulator::{
+    OutputSettingsBuilder,
+    SimulatorDisplay,
+    SimulatorEvent,
+    Window,
+};
+use rs_embedded_fish::{FishTank, rgb565};
+
+// The sprite data is read as u16 words, so it has to be 2-byte aligned,
+// which include_bytes! doesn't promise on its own.
+#[repr(align(2))]
+struct Aligned<T: ?Sized>(T);
+
+static SPRITE_DATA: &Aligned<[u8]> =
+    &Aligned(*include_bytes!("../src/fish.raw"));
+
+// Same size as the Longan Nano's LCD.
+const WIDTH: u32 = 160;
+const HEIGHT: u32 = 80;
+
+// Same as the Longan Nano program's TARGET_FPS.
+const FRAME_TIME: Duration = Duration::from_millis(50);
+
+fn main() {
+    let mut display: SimulatorDisplay<Rgb565> =
+        SimulatorDisplay::new(Size::new(WIDTH, HEIGHT));
+    let output_settings = OutputSettingsBuilder::new().scale(4).build();
+    let mut window = Window::new("Fish tank", &output_settings);
+
+    let mut fish_tank = FishTank::new(display.size(), &SPRITE_DATA.0,
+                                      0x1badd00d8badf00d).unwrap();
+
+    display.clear(rgb565(fish_tank.background_color())).unwrap();
+    fish_tank.render_all(&mut display).unwrap();
+
+    'running: loop {
+        window.update(&display);
+        for event in window.events() {
+            if let SimulatorEvent::Quit = event {
+                break 'running;
+            }
+        }
+
+        thread::sleep(FRAME_TIME);
+        fish_tank.swim();
+        fish_tank.render(&mut display).unwrap();
+    }
+}
@@ -0,0 +1,6257 @@
+//! An animated fish tank, which can be drawn on any embedded-graphics
+//! `DrawTarget` that accepts `Rgb565` pixels, or on a grayscale or
+//! one-bit display through `GrayTarget` or `BinaryTarget`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+use byte_slice_cast::AsSliceOf;
+use core::convert::{TryFrom, TryInto};
+use core::ops::{Add, Range};
+use embedded_graphics::drawable::Pixel;
+use embedded_graphics::geometry::{Point, Size};
+use embedded_graphics::pixelcolor::{BinaryColor, Gray8, Rgb565};
+use embedded_graphics::pixelcolor::raw::{RawData, RawU16};
+use embedded_graphics::prelude::DrawTarget;
+use embedded_graphics::primitives::Rectangle;
+use rand::Rng;
+use rand::distributions::uniform::SampleUniform;
+use rand_pcg::Pcg32;
+#[cfg(feature = "save-state")]
+use serde::{Deserialize, Serialize};
+
+/// Number of fish on the screen at once.  Does not have to equal NUM_SPRITES.
+pub const NUM_FISH: usize = 10;
+
+/// Maximum number of fish, when fish are added with `add_fish`.
+pub const MAX_FISH: usize = 20;
+
+// The screen is divided into this many horizontal bands, and each band
+// keeps track of which fish overlap it, so that get_point() only has to
+// look at those fish.
+const NUM_BANDS: usize = 16;
+
+// The fish in each band are kept as a bitmask in a u32, so there can't
+// be more than 32 of them.  This fails to compile if there are.
+const _: usize = 32 - MAX_FISH;
+
+/// For the two fish that are animated, controls how fast their mouths
+/// open and close.  Larger is slower.  1 is fastest.  This is the
+/// default; each fish can have its own speed (see `TankConfig`).
+pub const ANIMATION_SPEED: u8 = 2;
+
+/// Color of the water, in RGB565 format.
+pub const BACKGROUND: u16 = 0x1f;   // blue
+
+/// Color of the water at night, if the day/night cycle is enabled.
+pub const NIGHT_BACKGROUND: u16 = 0x46;     // dark blue
+
+/// Maximum number of bubbles in the tank at once.
+pub const MAX_BUBBLES: usize = 8;
+
+/// Color of the bubbles, in RGB565 format.
+pub const BUBBLE_COLOR: u16 = 0xa69f;   // light blue
+
+// Range of bubble radii, in pixels.
+const BUBBLE_RADIUS: Range<i32> = 1..4;
+
+// How much a bubble in front of a fish tints it, out of 255.
+const BUBBLE_ALPHA: u8 = 96;
+
+/// Color of the seaweed, in RGB565 format.
+pub const SEAWEED_COLOR: u16 = 0x1504;  // dark green
+
+/// Maximum number of fish in the distance, behind everything else.
+pub const MAX_BG_FISH: usize = 4;
+
+// Scale of the fish in the distance, in units of 1/FULL_SCALE.
+const BG_SCALE: u8 = 6;
+
+// How far the fish in the distance fade into the water, out of 255.
+const BG_DIM: u8 = 128;
+
+/// Maximum number of food pellets in the tank at once.
+pub const MAX_FOOD: usize = 8;
+
+/// Color of the food pellets, in RGB565 format.
+pub const FOOD_COLOR: u16 = 0xcc00;     // brown
+
+// Width and height of a food pellet, in pixels.
+const FOOD_SIZE: i32 = 2;
+
+// Number of calls to swim() for a food pellet to sink by one pixel.
+const FOOD_SINK_SPEED: u32 = 2;
+
+/// Color of the sand at the bottom of the tank, in RGB565 format.
+pub const SAND_COLOR: u16 = 0xe6b1;     // pale yellow
+
+/// Color of the rocks, in RGB565 format.
+pub const ROCK_COLOR: u16 = 0x738e;     // gray
+
+/// Color of the boxes drawn around the fish with `debug_boxes`, in
+/// RGB565 format.
+pub const DEBUG_BOX_COLOR: u16 = 0xf81f;    // magenta
+
+// Distance between the bands of light from caustics, in pixels
+// measured along a row.
+const CAUSTIC_PERIOD: u32 = 32;
+
+// Width of a band of light, and how much it brightens the water at its
+// middle, in 5-bit color units.
+const CAUSTIC_WIDTH: i32 = 8;
+const CAUSTIC_BRIGHTNESS: i32 = 4;
+
+// Number of calls to intro_frame() for the water to fill the tank.
+const INTRO_FRAMES: u32 = 20;
+
+// Width of a strand of seaweed, in pixels.
+const SEAWEED_WIDTH: i32 = 2;
+
+// Horizontal offset of each row of seaweed, as the sway moves up the
+// strand.
+const SWAY: [i32; 8] = [0, 1, 2, 1, 0, -1, -2, -1];
+
+// Number of calls to swim() before the sway moves on to the next offset.
+const SWAY_SPEED: u32 = 4;
+
+/// Scales are expressed in units of 1/FULL_SCALE.
+pub const FULL_SCALE: u8 = 16;
+
+// These three constants are baked into fish.raw, so don't change them
+// unless fish.raw changes.  Sprite data with a different number of
+// frames can be used by giving FishTank a different FRAMES parameter,
+// in which case each sprite's header has FRAMES frame offsets instead
+// of NUM_FRAMES.
+//
+// The sprite data is an array of native-endian u16 words, starting
+// with a header which describes each sprite, followed by the pixels of
+// each frame in row-major order.  Frame offsets in the header count
+// words from the start of the sprite data.  There are five versions of
+// the header:
+//
+// Version 1 (the original fish.raw) has 4 words per sprite:
+//   word 0:     (width << 8) | height
+//   words 1..3: offset of each frame
+//
+// Version 2 starts with the word (2 << 8), whose low byte of zero can
+// never occur in version 1 since it would mean a height of zero.  It is
+// followed by 8 words per sprite:
+//   word 0:     width
+//   word 1:     height
+//   words 2..7: offset of each frame, as 32 bits, low word first
+//
+// Version 3 starts with the word (3 << 8), followed by 9 words per
+// sprite:
+//   word 0:     width
+//   word 1:     height
+//   word 2:     flags
+//   words 3..8: offset of each frame, as 32 bits, low word first
+//
+// Version 4 starts with the word (4 << 8), followed by 10 words per
+// sprite.  It is the same as version 3, except that word 3 is the
+// sprite's transparent color, and the frame offsets are in words 4..9.
+// In the other versions, the transparent color is TRANSPARENT.
+//
+// Version 5 starts with the word (5 << 8), followed by the number of
+// sprites, and then the same as version 4.  The other versions always
+// have NUM_SPRITES sprites.
+//
+// If bit 0 of the flags (RLE_FLAG) is clear, each frame is stored as
+// width * height pixels, just like versions 1 and 2.  If it is set,
+// each frame is run-length encoded, one row at a time:
+//   words 0..height-1: offset of each row's runs, counting words from
+//                      the start of the frame
+//   then for each row, pairs of words (count, pixel) which add up to
+//   width pixels.
+// The rows are encoded separately so that any pixel can be found
+// without decoding the whole frame.
+//
+// If bit 1 of the flags (PALETTE_FLAG) is set, the sprite has a
+// palette of 16 colors, stored in the 16 words just before frame 0.
+// Each frame is stored as width * height 4-bit indices into the
+// palette, in row-major order, packed four to a word starting with the
+// least significant bits.  Index 0 is transparent, so palette entry 0
+// is never used.  RLE_FLAG and PALETTE_FLAG can't both be set.
+//
+// If bit 2 of the flags (ALPHA_FLAG) is set as well as PALETTE_FLAG,
+// palette indices 8..15 are translucent, and are blended with whatever
+// is behind the sprite.  The low byte of palette entry 0 is how opaque
+// they are, out of 255.  Indices 1..7 are opaque as usual.  ALPHA_FLAG
+// can't be set without PALETTE_FLAG.
+//
+// Bits 8..15 of the flags are the number of frames the sprite actually
+// has, which may be fewer than the number of frame offsets, for sprites
+// which animate more slowly or not at all.  The offsets of the missing
+// frames are ignored.  Zero means every frame is there, which is always
+// the case in versions 1 and 2.
+//
+// In every version, a frame offset of 0 means the frame is the same as
+// frame 0, since the header is always at offset 0.  Frame 0 itself
+// can't have an offset of 0.
+pub const NUM_FRAMES: usize = 3;
+pub const NUM_SPRITES: usize = 10;
+/// The transparent color of sprites whose header doesn't specify one.
+pub const TRANSPARENT: u16 = 0xdead;
+
+/// Default weights of the three rules fish follow when flocking (see
+/// `TankConfig`): steering towards the middle of the school, away from
+/// fish which are too close, and the same way as the rest of the school.
+pub const COHESION: i32 = 1;
+pub const SEPARATION: i32 = 4;
+pub const ALIGNMENT: i32 = 12;
+
+/// Color that rare golden fish are tinted towards, in RGB565 format.
+pub const GOLD: u16 = 0xfea0;
+
+// How far golden fish are tinted towards GOLD, out of 255.
+const GOLD_TINT: u8 = 160;
+
+// Number of calls to swim() it takes a fish to turn around, if turning is
+// enabled in the TankConfig.  Step k of the turn (k = 1 to TURN_FRAMES -
+// 1) is drawn squashed horizontally about the middle of the fish, to
+// max(|TURN_FRAMES - 2k|, 1) / TURN_FRAMES of its width, facing the old
+// way for the first half of the turn and the new way for the second.
+// Measuring in half pixels from the middle of the fish, the column at
+// offset o shows the sprite's column at offset o * TURN_FRAMES / that
+// width, and is transparent if that lies outside the sprite.
+const TURN_FRAMES: u8 = 6;
+
+// One cycle of a sine wave, scaled to +/- 64, which bobbing fish follow
+// up and down as they swim.
+const BOB_WAVE: [i32; 16] = [
+    0, 24, 45, 59, 64, 59, 45, 24, 0, -24, -45, -59, -64, -59, -45, -24,
+];
+
+// Number of calls to swim() before a bobbing fish moves on to the next
+// entry of BOB_WAVE.
+const BOB_SPEED: u32 = 4;
+
+// Each bobbing fish is given a random amplitude, in pixels, from this
+// range.
+const BOB_AMPLITUDE: Range<i32> = 2..6;
+
+// A fish which stops to hover stays put for a random number of calls
+// to swim() from this range.
+const HOVER_FRAMES: Range<u8> = 10..40;
+
+// Fish which accelerate, or are carried by a current, keep track of
+// where they are, and how fast they are going, in units of 1/SUBPIXELS
+// of a pixel.
+const SUBPIXELS: i32 = 16;
+
+// Most that avoiding other fish can change a fish's vertical speed by
+// in one step, however many fish it is crowded by.
+const AVOID_NUDGE: i32 = 1;
+
+/// Which sprite the predator is drawn with, if there is one.  This is
+/// the biggest fish in fish.raw.
+pub const PREDATOR_SPRITE: usize = 9;
+
+/// The frame of animation in which the animated fish in fish.raw have
+/// their mouths open.
+pub const MOUTH_OPEN_FRAME: u8 = 2;
+
+const RLE_FLAG: u16 = 1;
+const PALETTE_FLAG: u16 = 2;
+const ALPHA_FLAG: u16 = 4;
+
+// With ALPHA_FLAG, palette indices from this one up are translucent.
+const ALPHA_INDEX: u16 = 8;
+
+// Number of colors in a sprite's palette, including the unused one.
+const PALETTE_SIZE: usize = 16;
+
+pub enum PointValue {
+    OutOfRange,
+    Transparent,
+    Opaque(u16),
+    /// A color to be blended with whatever is behind it, and how opaque
+    /// it is, out of 255.  `FishTank::get_point` does the blending, so
+    /// only sprites and fish return this.
+    Translucent(u16, u8),
+}
+
+/// Something which happened during the most recent call to swim() or
+/// step(), from `FishTank::events`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Event {
+    /// The fish with this index opened its mouth, by moving on to
+    /// `MOUTH_OPEN_FRAME`.  Only fish whose sprite looks different in
+    /// that frame than in the first one open their mouths.
+    MouthOpen(usize),
+}
+
+/// Reasons why a sprite could not be loaded from the sprite data.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SpriteError {
+    /// The sprite's header lies beyond the end of the sprite data.
+    HeaderOutOfRange,
+    /// One of the sprite's frames extends beyond the end of the sprite data.
+    FrameOutOfRange,
+    /// The sprite's width or height is zero.
+    ZeroDimension,
+    /// The sprite data has a header version which isn't understood.
+    UnknownVersion,
+    /// A row of a run-length encoded frame doesn't add up to the
+    /// sprite's width.
+    BadRunLength,
+    /// The sprite's flags ask for more than one encoding.
+    BadFlags,
+    /// The sprite's flags say it has more frames than there are
+    /// frame offsets for.
+    BadFrameCount,
+    /// The sprite's first frame has an offset of 0, which only the other
+    /// frames may have, to say that they are the same as the first.
+    NoFirstFrame,
+    /// The sprite data has fewer sprites than the sprite number.
+    NoSuchSprite,
+}
+
+/// Reasons why a `FishTank` could not be created.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TankError {
+    /// The sprite data can't be read as u16 words, because its length is
+    /// odd or it isn't aligned to a 2-byte boundary.
+    BadSpriteData,
+    /// One of the sprites in the sprite data couldn't be loaded.
+    BadSprite(SpriteError),
+    /// A sprite number which isn't in the sprite data was asked for,
+    /// either in the species, or as `PREDATOR_SPRITE` for the predator.
+    NoSuchSprite,
+    /// The species weights add up to zero, or don't fit in a u32.
+    BadWeights,
+    /// None of the sprites, or none of the species with a weight, are
+    /// within `sprite_area`.
+    NoSpriteInRange,
+    /// A `TankState` has more fish, bubbles, or food than there is room
+    /// for, or a fish with a sprite number which isn't in the sprite
+    /// data.
+    BadState,
+    /// The buffer given to `render_to_slice` is too small for the
+    /// display, or its stride is narrower than the display, or the one
+    /// given to `render_row` is narrower than the display.
+    BufferTooSmall,
+}
+
+impl From<SpriteError> for TankError {
+    fn from(err: SpriteError) -> TankError {
+        TankError::BadSprite(err)
+    }
+}
+
+// How the pixels of a sprite's frames are stored.
+#[derive(PartialEq, Copy, Clone)]
+enum Encoding {
+    Raw,
+    RunLength,
+    Palette,
+}
+
+#[derive(PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "save-state", derive(Serialize, Deserialize))]
+pub enum Dir {
+    Left,
+    Right,
+}
+
+/// How a fish decides where to swim.
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "save-state", derive(Serialize, Deserialize))]
+pub enum Behavior {
+    /// Swims in a straight line, apart from a little random wobble.
+    Drift,
+    /// Chases the nearest `Prey`.
+    Predator,
+    /// Gets chased by a `Predator`.
+    Prey,
+}
+
+/// Which way up the tank is drawn on the display, for displays which are
+/// mounted rotated.  The display's own orientation is taken to be
+/// landscape, like the Longan Nano's LCD.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Orientation {
+    /// The same way up as the display.
+    Landscape,
+    /// Rotated a quarter turn clockwise, so the top of the tank is along
+    /// the right edge of the display.
+    Portrait,
+    /// Upside down.
+    LandscapeFlipped,
+    /// Rotated a quarter turn anticlockwise, so the top of the tank is
+    /// along the left edge of the display.
+    PortraitFlipped,
+}
+
+/// What happens to a fish which swims off the edge of the screen.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum EdgeBehavior {
+    /// It is randomized, and comes back in somewhere else, maybe from
+    /// the other side, at a new speed.
+    Randomize,
+    /// It comes straight back in from the opposite edge, at the same
+    /// height and speed.
+    Wrap,
+    /// It never gets that far, because it bounces off the edges of the
+    /// screen and the top of the sand, turning round when it bounces off
+    /// the sides.
+    Bounce,
+}
+
+/// The order a fish goes through the frames of its sprite in.
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "save-state", derive(Serialize, Deserialize))]
+pub enum AnimationMode {
+    /// From the first frame to the last, then straight back to the
+    /// first: 0, 1, 2, 0, 1, 2, ...
+    Loop,
+    /// From the first frame to the last, then back down again, so that
+    /// a mouth opens and closes smoothly: 0, 1, 2, 1, 0, 1, ...
+    PingPong,
+}
+
+/// What makes a fish move on to the next frame of its sprite.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum AnimationTiming {
+    /// Every `animation_speed` calls to swim(), whatever the fish does.
+    Time,
+    /// Every `animation_speed` pixels the fish swims, so that a fish
+    /// which stops stops wiggling its tail, and a fast fish wiggles it
+    /// faster.  Being carried by the current or bobbing up and down
+    /// doesn't count.
+    Distance,
+}
+
+/// How the fish are spread out when the tank is filled.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum SpawnStrategy {
+    /// Each fish is put anywhere, so several may start out on top of
+    /// each other.
+    Random,
+    /// The water is split into a band for each fish, from top to
+    /// bottom, and each fish starts out in a different band.
+    Stratified,
+}
+
+#[derive(Copy, Clone)]
+pub struct Sprite<'a, const FRAMES: usize = NUM_FRAMES> {
+    size: Size,
+    encoding: Encoding,
+    // Pixels of this color aren't drawn.
+    transparent: u16,
+    // Only used by Encoding::Palette.
+    palette: &'a [u16],
+    // How opaque the translucent palette entries are, out of 255, or
+    // 255 if there aren't any.
+    alpha:   u8,
+    // Only the first frame_count frames are valid.
+    frame_count: u8,
+    frames: [&'a [u16]; FRAMES],
+}
+
+/// A `Sprite` with `NUM_FRAMES` frames, like the ones in fish.raw.
+pub type DefaultSprite<'a> = Sprite<'a, NUM_FRAMES>;
+
+#[derive(Copy, Clone)]
+pub struct Fish<'a, const FRAMES: usize = NUM_FRAMES> {
+    fish_type:       Sprite<'a, FRAMES>,
+    upper_left:      Point,
+    // Where the fish was when the previous frame was drawn, and how big
+    // it was, so that its old position can be erased.
+    prev_upper_left: Point,
+    prev_size:       Size,
+    size:            Size,
+    direction:       Dir,
+    animation:       u8,
+    // Number of calls to swim() per animation frame.  0 is treated as 1.
+    animation_speed: u8,
+    animation_mode:  AnimationMode,
+    // With AnimationTiming::Distance, how far the fish has swum since
+    // the animation last moved on, in 1/SUBPIXELS of a pixel.
+    stride:          i32,
+    // Number of pixels moved each time the fish moves.  The direction
+    // only controls which way the sprite faces.
+    velocity:        Point,
+    // Number of pixels moved each time the fish moves, which a fish
+    // that steers keeps while it changes direction.
+    speed:           i32,
+    // The velocity the fish was given when it was randomized, which prey
+    // go back to once they have gotten away from the predator.
+    heading:         Point,
+    behavior:        Behavior,
+    // Which sprite the fish is drawn with.  Fish of the same species
+    // school together when flocking is enabled.
+    species:         usize,
+    // Golden fish are tinted gold, and swim twice as fast.
+    golden:          bool,
+    // Number of steps left in the fish's turn, or 0 if it isn't turning.
+    turn:            u8,
+    // Fish with a larger depth are drawn in front of fish with a
+    // smaller depth.
+    depth:           u8,
+    // Size the sprite is drawn at, in units of 1/FULL_SCALE.
+    scale:           u8,
+    // Where a bobbing fish starts in BOB_WAVE, and how far it bobs up
+    // and down from where it would otherwise be.
+    bob_phase:       u8,
+    bob_amplitude:   i32,
+    // How far the fish is currently bobbed down, or None if it hasn't
+    // bobbed since it was randomized.
+    bob:             Option<i32>,
+    // Number of steps left for the fish to hover in place, not swimming
+    // forwards, or 0 if it isn't hovering.
+    hover_frames:    u8,
+    // Upside-down fish are drawn mirrored top to bottom, as well as
+    // left to right when they face left.
+    flip_v:          bool,
+    // With acceleration, how fast the fish is actually going, which
+    // eases towards its velocity.  With acceleration or a current, how
+    // far it is past upper_left.  Both are in 1/SUBPIXELS of a pixel.
+    glide:           Point,
+    subpixel:        Point,
+    // Number of calls to swim() since the fish was randomized, and how
+    // many it lives for, or 0 if it lives forever.
+    age:             u32,
+    max_age:         u32,
+}
+
+#[derive(Copy, Clone)]
+pub struct Bubble {
+    center:      Point,
+    // Where the bubble was when the previous frame was drawn.
+    prev_center: Point,
+    radius:      i32,
+    // Number of pixels the bubble rises each step.
+    rise:        i32,
+}
+
+// A food pellet, which sinks until it is eaten.
+#[derive(Copy, Clone)]
+struct Pellet {
+    upper_left:      Point,
+    // Where the pellet was when the previous frame was drawn.
+    prev_upper_left: Point,
+}
+
+/// A strand of seaweed, which sways back and forth.
+#[derive(Copy, Clone)]
+pub struct Seaweed {
+    // The bottom of the strand.
+    base:   Point,
+    height: u32,
+    // Offsets the sway, so that strands don't all move in unison.
+    phase:  u8,
+}
+
+/// A round rock resting on the bottom of the tank.
+#[derive(Copy, Clone)]
+pub struct Rock {
+    center: Point,
+    radius: i32,
+}
+
+/// The bottom of the tank: a band of sand, and rocks in front of it,
+/// which never move.  Everything else is drawn in front of the terrain.
+#[derive(Copy, Clone)]
+pub struct Terrain<'a> {
+    // Number of rows of sand at the bottom of the tank.
+    sand_height: u32,
+    rocks:       &'a [Rock],
+}
+
+/// Options which control the behavior of a `FishTank`.  The default
+/// values reproduce the original behavior.
+#[derive(Clone)]
+pub struct TankConfig<'a> {
+    /// Number of fish in the tank to begin with, up to `MAX_FISH`.
+    pub fish_count:      usize,
+    /// Each fish is given a random animation speed from this range.
+    pub animation_speed: Range<u8>,
+    /// The order the fish go through the frames of their sprites in.
+    pub animation_mode:  AnimationMode,
+    /// What makes the fish move on to the next frame of their sprites.
+    pub animation_timing: AnimationTiming,
+    /// If true, a fish which swims off the screen and is randomized keeps
+    /// its animation speed, and carries on from the same point in its
+    /// animation, so that its wiggle doesn't visibly start over.  Fish
+    /// always start out at a random point in their animation.
+    pub keep_animation_phase: bool,
+    /// Each fish is given a random swimming speed, in pixels per step,
+    /// from this range.
+    pub speed_range:     Range<i32>,
+    /// Each fish is given a random vertical speed, in pixels per step,
+    /// from this range.  Positive is downward.
+    pub drift_range:     Range<i32>,
+    /// Each fish is given a random depth from this range.  Fish which
+    /// are deeper are drawn in front.  Fish at the same depth are drawn
+    /// in the order they were created.
+    pub depth_range:     Range<u8>,
+    /// Scale of the fish at the back of `depth_range`, in units of
+    /// 1/FULL_SCALE.  Fish further forward are scaled up linearly,
+    /// until the fish at the front are drawn at full size.
+    pub min_scale:       u8,
+    /// How far the fish at the back of `depth_range` fade into the
+    /// water, out of 255.  Fish further forward fade less, until the
+    /// fish at the front aren't faded at all.  0 disables the haze.
+    pub max_haze:        u8,
+    /// Number of bubbles rising through the tank, up to `MAX_BUBBLES`.
+    /// 0 disables bubbles.
+    pub bubble_count:    usize,
+    /// Seaweed to plant in the tank.
+    pub seaweed:         &'a [Seaweed],
+    /// Number of calls to swim() for the water to fade from `day_color`
+    /// to `night_color` and back.  0 keeps the water at `day_color`.
+    pub day_length:      u32,
+    pub day_color:       u16,
+    pub night_color:     u16,
+    /// Color of the water at the bottom of the tank during the day.  The
+    /// water shades from `day_color` at the top to this at the bottom,
+    /// and all of it fades to `night_color` at night.
+    pub depth_color:     u16,
+    /// If not empty, the water is drawn with this pattern instead,
+    /// repeated across the tank.  It is `tile_width` pixels wide, and
+    /// as many rows as there are whole rows of pixels, in RGB565 format.
+    /// It still fades to `night_color` at night.
+    pub tile:            &'a [u16],
+    pub tile_width:      u32,
+    /// If true, the first fish is a predator, drawn with
+    /// `PREDATOR_SPRITE`, which chases the other fish.
+    pub predator:        bool,
+    /// The other fish flee from the predator when it comes within this
+    /// many pixels of them.
+    pub flee_radius:     i32,
+    /// If true, fish of the same species school together, steering by
+    /// the other fish of their species within `flock_radius` pixels.
+    /// The predator doesn't school, and prey only school when they
+    /// aren't fleeing.
+    pub flocking:        bool,
+    pub flock_radius:    i32,
+    /// Weights of the flocking rules.  A weight of 16 lets the rule
+    /// change the fish's velocity by about a pixel per step for each
+    /// pixel (or pixel per step, for alignment) it is off by.
+    pub cohesion:        i32,
+    pub separation:      i32,
+    pub alignment:       i32,
+    /// Pairs of (sprite number, weight).  Each fish is given one of
+    /// these sprites at random, with a probability proportional to its
+    /// weight.  If empty, the fish go through the sprites in order.
+    pub species:         &'a [(usize, u32)],
+    /// For each sprite number, the chance out of 255 that a fish drawn
+    /// with that sprite swims to the right, so 0 always swims to the
+    /// left and 255 always to the right.  Sprites past the end of the
+    /// table, which is all of them if it is empty, go either way with
+    /// equal odds.
+    pub direction_bias:  &'a [u8],
+    /// Only sprites whose area, width times height in pixels, is in this
+    /// range are given to fish, whether they are picked from `species`
+    /// or from all the sprites.  The predator is always
+    /// `PREDATOR_SPRITE`, whatever its size.
+    pub sprite_area:     Range<u32>,
+    /// Each time a fish is randomized, there is a 1 in `rare_odds`
+    /// chance that it is a golden fish.  0 means there are no golden
+    /// fish.
+    pub rare_odds:       u32,
+    /// If true, fish that change direction while on the screen turn
+    /// around over a few frames, instead of flipping instantly.
+    pub turning:         bool,
+    /// Fish which come within this many pixels of a food pellet swim
+    /// over and eat it.  Prey only go after food when they aren't
+    /// fleeing, and the predator never does.
+    pub food_radius:     i32,
+    /// Which way up the tank is drawn.  In a portrait orientation, the
+    /// tank is as wide as the display is tall, and the fish swim along
+    /// the display's long side.
+    pub orientation:     Orientation,
+    /// Each pixel of the tank is drawn as an `upscale` by `upscale`
+    /// block of pixels on the display, so that there are fewer pixels
+    /// to work out on a big display, at the cost of detail.  The tank,
+    /// and everything in it, is that many times smaller than the
+    /// display.  0 and 1 both draw the tank at the display's resolution.
+    pub upscale:         u32,
+    /// What happens to fish which swim off the screen.
+    pub edge_behavior:   EdgeBehavior,
+    /// How the fish are spread out when the tank is filled, by new() or
+    /// reset().  Fish which come back in after leaving the screen are
+    /// always put anywhere.
+    pub spawn:           SpawnStrategy,
+    /// If true, diagonal bands of light drift across the water.  This
+    /// changes the whole background every frame, so the entire screen is
+    /// redrawn each step.
+    pub caustics:        bool,
+    /// Sand and rocks at the bottom of the tank.  Fish are only placed
+    /// where they start out above the sand.
+    pub terrain:         Terrain<'a>,
+    /// Number of small, dim, slow fish swimming in the distance, behind
+    /// everything else, up to `MAX_BG_FISH`.
+    pub background_fish_count: usize,
+    /// If true, fish whose bounding boxes overlap by at least half of
+    /// the smaller one steer up or down, away from each other.  The
+    /// predator doesn't get out of the way.
+    pub avoid_collisions: bool,
+    /// If true, fish bob up and down along a sine wave as they swim,
+    /// instead of jittering up and down at random.
+    pub bobbing:         bool,
+    /// Each step, there is a 1 in `hover_odds` chance that a fish stops
+    /// swimming forwards for a little while, and hovers in place.  0
+    /// means fish never hover.
+    pub hover_odds:      u32,
+    /// Each step, there is a 1 in `reverse_odds` chance that a fish on
+    /// the screen turns around and swims back the way it came, turning
+    /// over a few frames if `turning` is set.  0 means fish only turn
+    /// around when they have to.
+    pub reverse_odds:    u32,
+    /// Each fish lives for a random number of calls to swim() from this
+    /// range.  Once it is that old, it swims straight off the screen,
+    /// and a new fish comes in instead.  A lifespan of 0, the default,
+    /// means fish live forever.
+    pub lifespan:        Range<u32>,
+    /// If more than 0, fish speed up and slow down smoothly, by at most
+    /// this many sixteenths of a pixel per step each step, gliding
+    /// instead of moving a whole pixel at a time or not at all.  Fish
+    /// start off from rest when they enter the screen.  0 means fish
+    /// move at a constant speed.
+    pub acceleration:    i32,
+    /// How far the water carries every fish each step, in sixteenths of
+    /// a pixel, so that fish swimming against it go slower, and fish
+    /// swimming with it go faster.  Positive is to the right and down.
+    pub current:         Point,
+    /// If true, bubbles rise in front of the fish, tinting them, instead
+    /// of behind them.
+    pub bubbles_in_front: bool,
+    /// If set, the tank starts out empty, this color, and fills with
+    /// water from the top over the first few calls to
+    /// `FishTank::intro_frame`, before the fish start swimming.
+    pub intro:           Option<u16>,
+    /// If true, an outline of each fish's bounding box is drawn over
+    /// everything else in `DEBUG_BOX_COLOR`, to help see what is being
+    /// erased and which fish are bumping into each other.  Only meant
+    /// for development.
+    pub debug_boxes:     bool,
+}
+
+/// Everything about a fish which changes as the tank runs, apart from
+/// its sprite, which is saved as a sprite number.
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "save-state", derive(Serialize, Deserialize))]
+pub struct FishState {
+    sprite:          usize,
+    upper_left:      (i32, i32),
+    prev_upper_left: (i32, i32),
+    prev_size:       (u32, u32),
+    direction:       Dir,
+    animation:       u8,
+    animation_speed: u8,
+    animation_mode:  AnimationMode,
+    stride:          i32,
+    velocity:        (i32, i32),
+    speed:           i32,
+    heading:         (i32, i32),
+    behavior:        Behavior,
+    golden:          bool,
+    turn:            u8,
+    depth:           u8,
+    scale:           u8,
+    bob_phase:       u8,
+    bob_amplitude:   i32,
+    bob:             Option<i32>,
+    hover_frames:    u8,
+    flip_v:          bool,
+    glide:           (i32, i32),
+    subpixel:        (i32, i32),
+    age:             u32,
+    max_age:         u32,
+}
+
+/// A snapshot of a `FishTank`, from `FishTank::save_state`, which can
+/// be put back with `FishTank::restore_state` to carry on exactly where
+/// it left off.  The sprites and the `TankConfig` aren't included, so
+/// the tank it is restored into must be built with the same ones.  With
+/// the `save-state` feature, it can be serialized with serde, for
+/// example with postcard to save it to flash.
+#[derive(Clone)]
+#[cfg_attr(feature = "save-state", derive(Serialize, Deserialize))]
+pub struct TankState<R = Pcg32> {
+    fish:          [FishState; MAX_FISH],
+    num_fish:      usize,
+    bg_fish:       [FishState; MAX_BG_FISH],
+    num_bg_fish:   usize,
+    bubbles:       [BubbleState; MAX_BUBBLES],
+    num_bubbles:   usize,
+    food:          [PelletState; MAX_FOOD],
+    num_food:      usize,
+    frame_counter: u32,
+    // The top left and bottom right of the areas left to erase.
+    vacated:       Option<((i32, i32), (i32, i32))>,
+    erasing:       Option<((i32, i32), (i32, i32))>,
+    paused:        bool,
+    brightness:    u8,
+    intro:         u32,
+    rng:           R,
+}
+
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "save-state", derive(Serialize, Deserialize))]
+struct BubbleState {
+    center:      (i32, i32),
+    prev_center: (i32, i32),
+    radius:      i32,
+    rise:        i32,
+}
+
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "save-state", derive(Serialize, Deserialize))]
+struct PelletState {
+    upper_left:      (i32, i32),
+    prev_upper_left: (i32, i32),
+}
+
+// Points and rectangles are saved as tuples, since embedded-graphics
+// doesn't support serde.
+fn point_state(pt: Point) -> (i32, i32) {
+    (pt.x, pt.y)
+}
+
+fn state_point((x, y): (i32, i32)) -> Point {
+    Point::new(x, y)
+}
+
+fn rect_state(rect: Rectangle) -> ((i32, i32), (i32, i32)) {
+    (point_state(rect.top_left), point_state(rect.bottom_right))
+}
+
+fn state_rect((top_left, bottom_right): ((i32, i32), (i32, i32)))
+              -> Rectangle {
+    Rectangle::new(state_point(top_left), state_point(bottom_right))
+}
+
+/// The random number generator defaults to `Pcg32`, but any `Rng` will
+/// do, such as a smaller one for a more constrained chip.  `FRAMES` is
+/// the number of frames of animation in each sprite of the sprite data.
+pub struct FishTank<'a, R = Pcg32, const FRAMES: usize = NUM_FRAMES> {
+    // Every sprite in the sprite data, indexed by sprite number.
+    sprites: [Sprite<'a, FRAMES>; NUM_SPRITES],
+    // Number of sprites actually in the sprite data, up to NUM_SPRITES.
+    // Only the first sprite_count entries of sprites are loaded.
+    sprite_count: usize,
+    fish:    [Fish<'a, FRAMES>;   MAX_FISH],
+    // Number of entries of fish which are in use.
+    num_fish: usize,
+    // Fish in the distance, which are drawn behind everything else and
+    // don't steer.
+    bg_fish: [Fish<'a, FRAMES>;   MAX_BG_FISH],
+    num_bg_fish: usize,
+    // Indices into the first num_fish entries of fish, from front to back.
+    order:   [usize;      MAX_FISH],
+    // Bit n of bands[b] is set if the fish at order[n] overlaps band b.
+    bands:   [u32;        NUM_BANDS],
+    bubbles: [Bubble;     MAX_BUBBLES],
+    // Number of entries of bubbles which are in use.
+    num_bubbles: usize,
+    food:    [Pellet;     MAX_FOOD],
+    // Number of entries of food which are in use.
+    num_food: usize,
+    // Size of the tank, which is the size of the display divided by
+    // upscale, and rotated to the configured orientation.  Everything
+    // but drawing works in the tank's coordinates.
+    size:    Size,
+    display_size: Size,
+    // The size of the display divided by upscale, before it is rotated.
+    scaled_size: Size,
+    rng:     R,
+    config:  TankConfig<'a>,
+    // Number of calls to swim() so far, which drives the seaweed and
+    // the day/night cycle.
+    frame_counter: u32,
+    // Areas where fish have been removed, which still need to be erased.
+    // Fish removed since the last call to swim() are in vacated, and
+    // they move to erasing when swim() is called, so that they are
+    // erased no matter which order swim() and render() are called in.
+    vacated: Option<Rectangle>,
+    erasing: Option<Rectangle>,
+    // If true, swim() leaves everything where it is.
+    paused:  bool,
+    // Every color drawn is scaled by brightness / 255.
+    brightness: u8,
+    // Number of calls to intro_frame() so far.  The intro is over once
+    // this is past INTRO_FRAMES.
+    intro:   u32,
+    // Bit i is set if fish i opened its mouth during the most recent
+    // call to swim().
+    mouths_opened: u32,
+    // A still image drawn in front of everything else, and where its
+    // upper left corner is, in the tank's coordinates.
+    overlay: Option<(Sprite<'a, FRAMES>, Point)>,
+    // Number of times point_behind() has looked at a fish, so that the
+    // tests can tell how many the bands save.
+    #[cfg(test)]
+    visits:  core::cell::Cell<u32>,
+}
+
+/// A `FishTank` which uses the default random number generator.
+pub type DefaultFishTank<'a> = FishTank<'a, Pcg32>;
+
+/// Seed used by `FishTankBuilder` unless it is given another one.
+pub const DEFAULT_SEED: u64 = 0x1badd00d8badf00d;
+
+/// Stream of the `Pcg32` which a `DefaultFishTank` is seeded with,
+/// unless it is given another one.
+pub const DEFAULT_STREAM: u64 = 0xdefacedbadfacade;
+
+/// Builds a `FishTank` one option at a time, for when `FishTank::new`
+/// isn't enough.  Options which aren't set keep their `TankConfig`
+/// defaults.
+#[derive(Clone)]
+pub struct FishTankBuilder<'a> {
+    sprite_bytes: &'a [u8],
+    // If not empty, the sprites come from these instead of sprite_bytes.
+    sheets:       &'a [&'a [u8]],
+    seed:         u64,
+    stream:       u64,
+    config:       TankConfig<'a>,
+}
+
+pub struct TankIterator<'a, R = Pcg32, const FRAMES: usize = NUM_FRAMES> {
+    tank:     &'a FishTank<'a, R, FRAMES>,
+    // The color of the water at the top and bottom of the tank, which
+    // don't change while drawing.
+    background: u16,
+    depth:    u16,
+    // Area of the screen being drawn.  bottom_right is inclusive.
+    bounds:   Rectangle,
+    // If true, pixels not covered by any fish are painted with the
+    // background.  If false, they are skipped.
+    erase:    bool,
+    position: Point,
+    // The last point in the tank which was worked out, and its color, or
+    // None if it was skipped, so that with upscale, the rest of the row
+    // of its block doesn't have to be worked out again.
+    cached:   Option<(Point, Option<u16>)>,
+}
+
+/// Groups the pixels from a `TankIterator` into runs of the same color
+/// along a row, for displays which can fill a run in one go.  Each item
+/// is the leftmost pixel of the run, its color in RGB565 format, and
+/// the number of pixels in the run.
+pub struct RunIterator<'a, R = Pcg32, const FRAMES: usize = NUM_FRAMES> {
+    pixels: TankIterator<'a, R, FRAMES>,
+    // The first pixel of the next run, which has already been taken
+    // from pixels.
+    next:   Option<(Point, u16)>,
+}
+
+/// A display which can draw a row of pixels in one go, for use with
+/// `FishTank::render_buffered`.
+pub trait LineTarget {
+    type Error;
+
+    /// Draw `pixels` from left to right, starting at `start`.
+    fn draw_row(&mut self,
+                start: Point,
+                pixels: &[Rgb565]) -> Result<(), Self::Error>;
+}
+
+/// Draws rows one pixel at a time on any `DrawTarget`, for displays
+/// which have no faster way to do it.
+pub struct PixelRows<'a, D>(pub &'a mut D);
+
+impl<D: DrawTarget<Rgb565>> LineTarget for PixelRows<'_, D> {
+    type Error = D::Error;
+
+    fn draw_row(&mut self,
+                start: Point,
+                pixels: &[Rgb565]) -> Result<(), Self::Error> {
+        self.0.draw_iter(pixels.iter().zip(start.x..).map(|(&c, x)| {
+            Pixel(Point::new(x, start.y), c)
+        }))
+    }
+}
+
+/// Draws on a grayscale display, such as many OLEDs, through any
+/// `DrawTarget` which accepts `Gray8` pixels.  Each pixel is turned
+/// into a shade of gray with `luma565()`.
+pub struct GrayTarget<'a, D>(pub &'a mut D);
+
+impl<D: DrawTarget<Gray8>> DrawTarget<Rgb565> for GrayTarget<'_, D> {
+    type Error = D::Error;
+
+    fn draw_pixel(&mut self, pixel: Pixel<Rgb565>) -> Result<(), D::Error> {
+        let Pixel(pt, color) = pixel;
+        let luma = luma565(RawU16::from(color).into_inner());
+        self.0.draw_pixel(Pixel(pt, Gray8::new(luma)))
+    }
+
+    fn size(&self) -> Size {
+        self.0.size()
+    }
+}
+
+/// Draws on a one-bit display through any `DrawTarget` which accepts
+/// `BinaryColor` pixels.  A pixel is `On` if its `luma565()` is at
+/// least `threshold`, and `Off` otherwise.
+pub struct BinaryTarget<'a, D> {
+    pub target:    &'a mut D,
+    pub threshold: u8,
+}
+
+impl<D> DrawTarget<Rgb565> for BinaryTarget<'_, D>
+where D: DrawTarget<BinaryColor>,
+{
+    type Error = D::Error;
+
+    fn draw_pixel(&mut self, pixel: Pixel<Rgb565>) -> Result<(), D::Error> {
+        let Pixel(pt, color) = pixel;
+        let luma = luma565(RawU16::from(color).into_inner());
+        let on = if luma >= self.threshold {
+            BinaryColor::On
+        } else {
+            BinaryColor::Off
+        };
+        self.target.draw_pixel(Pixel(pt, on))
+    }
+
+    fn size(&self) -> Size {
+        self.target.size()
+    }
+}
+
+// The random number generator a DefaultFishTank uses for a seed and a
+// stream.  Pcg32 has no bad seeds, even ones which differ by a single
+// bit are scrambled into unrelated sequences, and any stream is as good
+// as any other (only its bottom 63 bits count).  But two tanks with the
+// same seed and stream are always the same, so boards which might end
+// up with the same seed can be kept apart by taking the stream from
+// somewhere else.
+fn seeded_rng(seed: u64, stream: u64) -> Pcg32 {
+    Pcg32::new(seed, stream)
+}
+
+/// Number of sprites in `sprite_data`, which is NUM_SPRITES unless the
+/// header says otherwise.
+pub fn sprite_count(sprite_data: &[u16]) -> usize {
+    match sprite_data {
+        [w, count, ..] if w & 0xff == 0 && w >> 8 == 5 => usize::from(*count),
+        _ => NUM_SPRITES,
+    }
+}
+
+// Conversions between the integer types used for sizes, coordinates,
+// and indexes.  A value which doesn't fit is clamped to the nearest one
+// which does, instead of panicking, since a panic on the Longan Nano
+// just hangs with nothing on the screen to say why.
+
+/// Converts a size to a coordinate, clamping it to `i32::MAX`.
+pub fn cvt(u: u32) -> i32 {
+    i32::try_from(u).unwrap_or(i32::MAX)
+}
+
+// A coordinate as a size, with negative ones clamped to 0.
+fn to_size(i: i32) -> u32 {
+    u32::try_from(i).unwrap_or(0)
+}
+
+// A coordinate as an index, with negative ones clamped to 0.
+fn to_index(i: i32) -> usize {
+    usize::try_from(i).unwrap_or(0)
+}
+
+// A size as an index, clamped to usize::MAX.
+fn size_index(u: u32) -> usize {
+    usize::try_from(u).unwrap_or(usize::MAX)
+}
+
+pub fn rgb565(packed: u16) -> Rgb565 {
+    Rgb565::from(RawU16::new(packed))
+}
+
+// Interpolate between two RGB565 colors, one channel at a time.  An
+// amount of 0 gives `from` and 255 gives `to`.
+fn lerp565(from: u16, to: u16, amount: u8) -> u16 {
+    let amount = i32::from(amount);
+    let channel = |shift: u32, mask: u16| {
+        let a = i32::from((from >> shift) & mask);
+        let b = i32::from((to >> shift) & mask);
+        let c: u16 = (a + (b - a) * amount / 255).try_into().unwrap();
+        c << shift
+    };
+    channel(11, 0x1f) | channel(5, 0x3f) | channel(0, 0x1f)
+}
+
+/// The red, green, and blue channels of an RGB565 color, each scaled up
+/// to 0..255 by repeating its top bits below the rest, so that white
+/// stays white.
+pub fn rgb888(c: u16) -> [u8; 3] {
+    let r = ((c >> 11) & 0x1f) as u8;
+    let g = ((c >> 5) & 0x3f) as u8;
+    let b = (c & 0x1f) as u8;
+    [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+}
+
+/// Brightness of an RGB565 color, from 0 for black to 255 for white.
+/// The channels are widened to 8 bits with `rgb888()`, as for a
+/// screenshot, and weighted as in ITU-R BT.601, since green looks
+/// brightest.
+pub fn luma565(c: u16) -> u8 {
+    let [r, g, b] = rgb888(c);
+    let (r, g, b) = (u32::from(r), u32::from(g), u32::from(b));
+    ((299 * r + 587 * g + 114 * b + 500) / 1000).try_into().unwrap()
+}
+
+// The decimal digits of `n`, most significant first.
+fn decimal(n: usize) -> impl Iterator<Item = u8> {
+    let digits = core::iter::successors(Some(n), |&n| Some(n / 10))
+        .take_while(|&n| n > 0)
+        .count()
+        .max(1);
+    (0..digits).rev().map(move |i| {
+        let digit = n / 10usize.pow(i.try_into().unwrap()) % 10;
+        b'0' + u8::try_from(digit).unwrap()
+    })
+}
+
+/// The ASCII header of a binary PPM image of `width` by `height`
+/// pixels, such as "P6\n160 80\n255\n", for sending before the pixels
+/// when they are worked out a row at a time.
+pub fn ppm_header(width: usize, height: usize) -> impl Iterator<Item = u8> {
+    b"P6\n".iter().copied()
+        .chain(decimal(width))
+        .chain(core::iter::once(b' '))
+        .chain(decimal(height))
+        .chain(b"\n255\n".iter().copied())
+}
+
+/// `pixels`, rows of `width` RGB565 pixels, as a binary PPM image: the
+/// header from `ppm_header()`, followed by the bytes from `rgb888()` for
+/// each pixel, starting at the top left and going row by row.  Any
+/// partial row at the end is left out.
+pub fn ppm_bytes(pixels: &[u16], width: usize)
+                 -> impl Iterator<Item = u8> + '_ {
+    let height = pixels.len() / width.max(1);
+    ppm_header(width, height)
+        .chain(pixels[..width * height].iter().flat_map(|&p| rgb888(p)))
+}
+
+/// Blend `fg` over `bg`, one channel at a time, both in RGB565 format.
+/// An `alpha` of 255 gives `fg` and 0 gives `bg`.
+pub fn blend565(fg: u16, bg: u16, alpha: u8) -> u16 {
+    lerp565(bg, fg, alpha)
+}
+
+// Fade `color` into the water color `bg` behind it, for fish in the
+// distance.  An `amount` of 0 leaves the color alone, and 255 gives `bg`.
+fn haze(color: u16, bg: u16, amount: u8) -> u16 {
+    blend565(bg, color, amount)
+}
+
+// Add `amount` to each channel of an RGB565 color, doubled for the
+// 6-bit green channel, clamping at black and white.
+fn brighten565(color: u16, amount: i16) -> u16 {
+    let channel = |shift: u32, mask: u16, amount: i32| {
+        let c = i32::from((color >> shift) & mask) + amount;
+        let c: u16 = c.max(0).min(i32::from(mask)).try_into().unwrap();
+        c << shift
+    };
+    let amount = i32::from(amount);
+    channel(11, 0x1f, amount) | channel(5, 0x3f, amount * 2) |
+        channel(0, 0x1f, amount)
+}
+
+// A random position along one axis which keeps an object of length
+// `object` within a screen of length `screen`.  If the object doesn't
+// fit, it is placed at 0.
+fn random_offset<T: Rng>(screen: u32, object: u32, rng: &mut T) -> i32 {
+    if object >= screen {
+        0
+    } else {
+        cvt(rng.gen_range(0, screen - object))
+    }
+}
+
+// A random value from a range.  A range containing only one value
+// yields that value without using the random number generator, so that
+// the default configuration doesn't change the sequence of random
+// numbers.  An empty range yields its start.
+fn pick<T, R>(range: &Range<T>, rng: &mut R) -> T
+where T: SampleUniform + Copy + PartialOrd + Add<Output = T> + From<u8>,
+      R: Rng,
+{
+    if range.start < range.end && range.start + T::from(1) < range.end {
+        rng.gen_range(range.start, range.end)
+    } else {
+        range.start
+    }
+}
+
+// Velocities, and the distances between fish, are kept in Points, which
+// embedded-graphics can already add, subtract, and multiply or divide by
+// an i32.  These do the rest of the sums on them.
+
+// v with each coordinate limited to between -limit and limit
+// separately, which stops the fish going more than `limit` pixels a
+// step either way.  This doesn't keep the direction: (5, -9) limited
+// to 3 is (3, -3).  Limiting the length instead would leave a fish
+// going 1 pixel a step no diagonal steps at all, since (1, 1) is
+// already longer than that.
+fn clamp_axes(v: Point, limit: i32) -> Point {
+    let clamp = |c: i32| c.max(-limit).min(limit);
+    Point::new(clamp(v.x), clamp(v.y))
+}
+
+// v divided by `divisor`, rounding each coordinate down instead of
+// towards 0, so that steps to the left and up aren't any smaller than
+// steps to the right and down.
+fn div_floor(v: Point, divisor: i32) -> Point {
+    Point::new(v.x.div_euclid(divisor), v.y.div_euclid(divisor))
+}
+
+// The square of v's length, for comparing distances without a square
+// root.
+fn length2(v: Point) -> i32 {
+    v.x * v.x + v.y * v.y
+}
+
+// The union of two boxes, whose upper left corners are a and b,
+// clipped to the screen.  Returns None if that area is entirely off
+// the screen.
+fn union_rect(a: Point, a_size: Size,
+              b: Point, b_size: Size,
+              screen: &Size) -> Option<Rectangle> {
+    let left   = a.x.min(b.x).max(0);
+    let top    = a.y.min(b.y).max(0);
+    let right  = (a.x + cvt(a_size.width)).max(b.x + cvt(b_size.width)) - 1;
+    let bottom = (a.y + cvt(a_size.height)).max(b.y + cvt(b_size.height)) - 1;
+    let right  = right.min(cvt(screen.width) - 1);
+    let bottom = bottom.min(cvt(screen.height) - 1);
+    if left > right || top > bottom {
+        None
+    } else {
+        Some(Rectangle::new(Point::new(left, top),
+                            Point::new(right, bottom)))
+    }
+}
+
+// The smallest rectangle containing both rectangles.
+fn merge_rects(a: Option<Rectangle>, b: Option<Rectangle>) -> Option<Rectangle> {
+    match (a, b) {
+        (Some(a), Some(b)) =>
+            Some(Rectangle::new(
+                Point::new(a.top_left.x.min(b.top_left.x),
+                           a.top_left.y.min(b.top_left.y)),
+                Point::new(a.bottom_right.x.max(b.bottom_right.x),
+                           a.bottom_right.y.max(b.bottom_right.y)))),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+impl<const FRAMES: usize> Sprite<'_, FRAMES> {
+    /// Number of frames of animation the sprite has, from 1 to `FRAMES`.
+    pub fn frame_count(&self) -> u8 {
+        self.frame_count
+    }
+
+    // Number of pixels in each frame of the sprite.
+    fn area(&self) -> u32 {
+        self.size.width * self.size.height
+    }
+
+    /// Sample the sprite, drawn at `scale`/FULL_SCALE of its size, with
+    /// nearest-neighbor scaling.  `animation` is the frame number, which
+    /// wraps around after the last frame.
+    pub fn get_point(&self, pt: &Point, animation: u8, scale: u8) -> PointValue {
+        let scale = i32::from(scale.max(1));
+        let x = pt.x * i32::from(FULL_SCALE) / scale;
+        let y = pt.y * i32::from(FULL_SCALE) / scale;
+        if x < 0 || y < 0 ||
+            x >= cvt(self.size.width) ||
+            y >= cvt(self.size.height) {
+            PointValue::Transparent
+        } else {
+            let x = to_index(x);
+            let y = to_index(y);
+            let frame_no = usize::from(animation % self.frame_count);
+            let c = self.pixel(frame_no, x, y);
+            if c == self.transparent {
+                PointValue::Transparent
+            } else if self.alpha != u8::MAX &&
+                self.palette_index(frame_no, x, y) >= ALPHA_INDEX {
+                PointValue::Translucent(c, self.alpha)
+            } else {
+                PointValue::Opaque(c)
+            }
+        }
+    }
+
+    // Whether the sprite has a MOUTH_OPEN_FRAME which looks different
+    // from the first frame.
+    fn opens_mouth(&self) -> bool {
+        let open = usize::from(MOUTH_OPEN_FRAME);
+        open < usize::from(self.frame_count) &&
+            self.frames[open] != self.frames[0]
+    }
+
+    // The pixel at (x, y) of a frame, which must be within the sprite.
+    fn pixel(&self, frame_no: usize, x: usize, y: usize) -> u16 {
+        let frame: &[u16] = self.frames[frame_no];
+        match self.encoding {
+            Encoding::Raw => {
+                let width = size_index(self.size.width);
+                frame[x + y * width]
+            }
+            Encoding::RunLength => rle_pixel(frame, x, y),
+            Encoding::Palette => {
+                let index = self.palette_index(frame_no, x, y);
+                if index == 0 {
+                    self.transparent
+                } else {
+                    self.palette[usize::from(index)]
+                }
+            }
+        }
+    }
+
+    // The palette index of the pixel at (x, y) of a frame of a sprite
+    // with a palette.
+    fn palette_index(&self, frame_no: usize, x: usize, y: usize) -> u16 {
+        let frame: &[u16] = self.frames[frame_no];
+        let width = size_index(self.size.width);
+        let n = x + y * width;
+        (frame[n / 4] >> (n % 4 * 4)) & 0xf
+    }
+
+    pub fn make_sprite(sprite_num: usize,
+                       sprite_data: &[u16])
+                       -> Result<Sprite<'_, FRAMES>, SpriteError> {
+        let header: SpriteHeader<FRAMES> = match sprite_data.first() {
+            Some(&w) if w & 0xff == 0 && w >> 8 == 2 =>
+                SpriteHeader::read_v2(sprite_num, sprite_data)?,
+            Some(&w) if w & 0xff == 0 && w >> 8 == 3 =>
+                SpriteHeader::read_v3(sprite_num, sprite_data, false)?,
+            Some(&w) if w & 0xff == 0 && w >> 8 == 4 =>
+                SpriteHeader::read_v3(sprite_num, sprite_data, true)?,
+            Some(&w) if w & 0xff == 0 && w >> 8 == 5 => {
+                if sprite_num >= sprite_count(sprite_data) {
+                    return Err(SpriteError::NoSuchSprite);
+                }
+                // Skipping the version word leaves the sprite count in
+                // its place, just before the headers.
+                SpriteHeader::read_v3(sprite_num, &sprite_data[1..], true)?
+            }
+            Some(&w) if w & 0xff == 0 =>
+                return Err(SpriteError::UnknownVersion),
+            _ => SpriteHeader::read_v1(sprite_num, sprite_data)?,
+        };
+
+        if header.width == 0 || header.height == 0 {
+            return Err(SpriteError::ZeroDimension);
+        }
+
+        if header.frames[0] == 0 {
+            return Err(SpriteError::NoFirstFrame);
+        }
+
+        let num_words = size_index(header.width * header.height);
+
+        let frame_count = match usize::from(header.flags >> 8) {
+            0 => FRAMES,
+            n if n <= FRAMES => n,
+            _ => return Err(SpriteError::BadFrameCount),
+        };
+
+        let mut sprite = Sprite {
+            size: Size::new(header.width, header.height),
+            encoding: match header.flags & (RLE_FLAG | PALETTE_FLAG |
+                                            ALPHA_FLAG) {
+                0            => Encoding::Raw,
+                RLE_FLAG     => Encoding::RunLength,
+                PALETTE_FLAG => Encoding::Palette,
+                f if f == PALETTE_FLAG | ALPHA_FLAG => Encoding::Palette,
+                _            => return Err(SpriteError::BadFlags),
+            },
+            transparent: header.transparent,
+            alpha: u8::MAX,
+            palette: &[],
+            frame_count: frame_count.try_into().unwrap(),
+            frames: [&[]; FRAMES],
+        };
+
+        if sprite.encoding == Encoding::Palette {
+            let end = header.frames[0];
+            sprite.palette = end.checked_sub(PALETTE_SIZE)
+                .and_then(|start| sprite_data.get(start..end))
+                .ok_or(SpriteError::FrameOutOfRange)?;
+            if header.flags & ALPHA_FLAG != 0 {
+                sprite.alpha = (sprite.palette[0] & 0xff).try_into().unwrap();
+            }
+        }
+
+        for frame in 0..frame_count {
+            let frame_index = header.frames[frame];
+            if frame_index == 0 {
+                sprite.frames[frame] = sprite.frames[0];
+                continue;
+            }
+            sprite.frames[frame] = match sprite.encoding {
+                Encoding::Raw => frame_index.checked_add(num_words)
+                    .and_then(|end| sprite_data.get(frame_index..end))
+                    .ok_or(SpriteError::FrameOutOfRange)?,
+                // The length of a run-length encoded frame isn't known
+                // until it has been checked.
+                Encoding::RunLength => {
+                    let rest = sprite_data
+                        .get(frame_index..)
+                        .ok_or(SpriteError::FrameOutOfRange)?;
+                    let len = rle_check(rest, header.width, header.height)?;
+                    &rest[..len]
+                }
+                Encoding::Palette => frame_index
+                    .checked_add((num_words + 3) / 4)
+                    .and_then(|end| sprite_data.get(frame_index..end))
+                    .ok_or(SpriteError::FrameOutOfRange)?,
+            };
+        }
+
+        Ok(sprite)
+    }
+}
+
+// Find a pixel in a run-length encoded frame, which must already have
+// been checked by rle_check().
+fn rle_pixel(frame: &[u16], x: usize, y: usize) -> u16 {
+    let mut i: usize = frame[y].into();
+    let mut end: usize = frame[i].into();
+    while x >= end {
+        i += 2;
+        end += usize::from(frame[i]);
+    }
+    frame[i + 1]
+}
+
+// Make sure every row of a run-length encoded frame lies within `frame`
+// and adds up to `width` pixels, so that rle_pixel() can't go wrong.
+// Returns the number of words the frame occupies.
+fn rle_check(frame: &[u16],
+             width: u32,
+             height: u32) -> Result<usize, SpriteError> {
+    let height = size_index(height);
+    let mut len = height;
+    for y in 0..height {
+        let mut i: usize = (*frame.get(y)
+                            .ok_or(SpriteError::FrameOutOfRange)?).into();
+        let mut x: u32 = 0;
+        while x < width {
+            let count = frame.get(i..i + 2)
+                .ok_or(SpriteError::FrameOutOfRange)?[0];
+            x += u32::from(count);
+            i += 2;
+        }
+
+        if x != width {
+            return Err(SpriteError::BadRunLength);
+        }
+        len = len.max(i);
+    }
+
+    Ok(len)
+}
+
+// The parts of a sprite's header which are needed to find its frames.
+struct SpriteHeader<const FRAMES: usize> {
+    width:  u32,
+    height: u32,
+    flags:  u16,
+    transparent: u16,
+    frames: [usize; FRAMES],
+}
+
+impl<const FRAMES: usize> SpriteHeader<FRAMES> {
+    fn read_v1(sprite_num: usize,
+               sprite_data: &[u16]) -> Result<Self, SpriteError> {
+        let num_words = 1 + FRAMES;
+        let header_index = num_words * sprite_num;
+        let words = sprite_data
+            .get(header_index..header_index + num_words)
+            .ok_or(SpriteError::HeaderOutOfRange)?;
+
+        let mut header = SpriteHeader {
+            width:  (words[0] >> 8).into(),
+            height: (words[0] & 0xff).into(),
+            flags:  0,
+            transparent: TRANSPARENT,
+            frames: [0; FRAMES],
+        };
+
+        for frame in 0..FRAMES {
+            header.frames[frame] = words[frame + 1].into();
+        }
+
+        Ok(header)
+    }
+
+    fn read_v2(sprite_num: usize,
+               sprite_data: &[u16]) -> Result<Self, SpriteError> {
+        let num_words = 2 + 2 * FRAMES;
+        let header_index = 1 + num_words * sprite_num;
+        let words = sprite_data
+            .get(header_index..header_index + num_words)
+            .ok_or(SpriteError::HeaderOutOfRange)?;
+
+        let mut header = SpriteHeader {
+            width:  words[0].into(),
+            height: words[1].into(),
+            flags:  0,
+            transparent: TRANSPARENT,
+            frames: [0; FRAMES],
+        };
+
+        for frame in 0..FRAMES {
+            let lo: u32 = words[2 + 2 * frame].into();
+            let hi: u32 = words[3 + 2 * frame].into();
+            header.frames[frame] = size_index(lo | hi << 16);
+        }
+
+        Ok(header)
+    }
+
+    // Reads version 3, or version 4 if `has_transparent` is true.
+    fn read_v3(sprite_num: usize,
+               sprite_data: &[u16],
+               has_transparent: bool) -> Result<Self, SpriteError> {
+        let extra = usize::from(has_transparent);
+        let num_words = 3 + extra + 2 * FRAMES;
+        let header_index = 1 + num_words * sprite_num;
+        let words = sprite_data
+            .get(header_index..header_index + num_words)
+            .ok_or(SpriteError::HeaderOutOfRange)?;
+
+        let mut header = SpriteHeader {
+            width:  words[0].into(),
+            height: words[1].into(),
+            flags:  words[2],
+            transparent: if has_transparent { words[3] } else { TRANSPARENT },
+            frames: [0; FRAMES],
+        };
+
+        for frame in 0..FRAMES {
+            let lo: u32 = words[3 + extra + 2 * frame].into();
+            let hi: u32 = words[4 + extra + 2 * frame].into();
+            header.frames[frame] = size_index(lo | hi << 16);
+        }
+
+        Ok(header)
+    }
+}
+
+impl<const FRAMES: usize> Fish<'_, FRAMES> {
+    fn state(&self) -> FishState {
+        FishState {
+            sprite:          self.species,
+            upper_left:      point_state(self.upper_left),
+            prev_upper_left: point_state(self.prev_upper_left),
+            prev_size:       (self.prev_size.width, self.prev_size.height),
+            direction:       self.direction,
+            animation:       self.animation,
+            animation_speed: self.animation_speed,
+            animation_mode:  self.animation_mode,
+            stride:          self.stride,
+            velocity:        point_state(self.velocity),
+            speed:           self.speed,
+            heading:         point_state(self.heading),
+            behavior:        self.behavior,
+            golden:          self.golden,
+            turn:            self.turn,
+            depth:           self.depth,
+            scale:           self.scale,
+            bob_phase:       self.bob_phase,
+            bob_amplitude:   self.bob_amplitude,
+            bob:             self.bob,
+            hover_frames:    self.hover_frames,
+            flip_v:          self.flip_v,
+            glide:           point_state(self.glide),
+            subpixel:        point_state(self.subpixel),
+            age:             self.age,
+            max_age:         self.max_age,
+        }
+    }
+
+    fn from_state<'a>(sprite: Sprite<'a, FRAMES>,
+                      state: &FishState) -> Fish<'a, FRAMES> {
+        let mut fish = Fish::new(sprite);
+        fish.species         = state.sprite;
+        fish.upper_left      = state_point(state.upper_left);
+        fish.prev_upper_left = state_point(state.prev_upper_left);
+        fish.prev_size       = Size::new(state.prev_size.0,
+                                         state.prev_size.1);
+        fish.direction       = state.direction;
+        fish.animation       = state.animation;
+        fish.animation_speed = state.animation_speed;
+        fish.animation_mode  = state.animation_mode;
+        fish.stride          = state.stride;
+        fish.velocity        = state_point(state.velocity);
+        fish.speed           = state.speed;
+        fish.heading         = state_point(state.heading);
+        fish.behavior        = state.behavior;
+        fish.golden          = state.golden;
+        fish.turn            = state.turn;
+        fish.depth           = state.depth;
+        fish.bob_phase       = state.bob_phase;
+        fish.bob_amplitude   = state.bob_amplitude;
+        fish.bob             = state.bob;
+        fish.hover_frames    = state.hover_frames;
+        fish.flip_v          = state.flip_v;
+        fish.glide           = state_point(state.glide);
+        fish.subpixel        = state_point(state.subpixel);
+        fish.age             = state.age;
+        fish.max_age         = state.max_age;
+        fish.set_scale(state.scale);
+        fish
+    }
+
+    pub fn get_point(&self, pt: &Point) -> PointValue {
+        if pt.x < self.upper_left.x ||
+            pt.y < self.upper_left.y ||
+            pt.x >= self.upper_left.x + cvt(self.size.width) ||
+            pt.y >= self.upper_left.y + cvt(self.size.height) {
+            PointValue::OutOfRange
+        } else {
+            let mut x = pt.x - self.upper_left.x;
+            let mut y = pt.y - self.upper_left.y;
+            let width = cvt(self.size.width);
+            let mut direction = self.direction;
+            if self.turn > 0 {
+                // See TURN_FRAMES for how this works.
+                let frames = i32::from(TURN_FRAMES);
+                let k = frames - i32::from(self.turn);
+                let squash = (frames - 2 * k).abs().max(1);
+                let offset = (2 * x + 1 - width) * frames / squash;
+                x = (offset + width - 1).div_euclid(2);
+                if x < 0 || x >= width {
+                    return PointValue::Transparent;
+                }
+                if 2 * k < frames {
+                    direction = match direction {
+                        Dir::Left  => Dir::Right,
+                        Dir::Right => Dir::Left,
+                    };
+                }
+            }
+            if direction == Dir::Left {
+                x = width - (x + 1);
+            }
+            // The two mirrors are independent, so an upside-down fish
+            // facing left is the sprite turned half way round.
+            if self.flip_v {
+                y = cvt(self.size.height) - (y + 1);
+            }
+            let pv = self.fish_type.get_point(
+                &Point::new(x, y),
+                self.frame(),
+                self.scale);
+            match pv {
+                PointValue::Opaque(c) if self.golden =>
+                    PointValue::Opaque(lerp565(c, GOLD, GOLD_TINT)),
+                PointValue::Translucent(c, alpha) if self.golden =>
+                    PointValue::Translucent(lerp565(c, GOLD, GOLD_TINT), alpha),
+                pv => pv,
+            }
+        }
+    }
+
+    /// Returns true unless the fish is entirely beyond one of the edges
+    /// of the screen.  A fish just touching an edge from outside counts
+    /// as on the screen, which is where randomize() puts it, so that it
+    /// isn't randomized again straight away, however big it is.
+    pub fn on_screen(&self, screen: &Size) -> bool {
+        self.upper_left.y <= cvt(screen.height) &&
+            self.upper_left.y + cvt(self.size.height) >= 0 &&
+            self.upper_left.x <= cvt(screen.width) &&
+            self.upper_left.x + cvt(self.size.width) >= 0
+    }
+
+    /// The union of the fish's previous and current bounding boxes,
+    /// clipped to the screen.  Returns None if that area is entirely
+    /// off the screen.
+    pub fn dirty_rect(&self, screen: &Size) -> Option<Rectangle> {
+        union_rect(self.prev_upper_left, self.prev_size,
+                   self.upper_left, self.size,
+                   screen)
+    }
+
+    // The frame of animation the fish is drawn with.
+    fn frame(&self) -> u8 {
+        let num_frames = self.fish_type.frame_count;
+        let step = self.animation / self.animation_speed() % self.cycle();
+        if step < num_frames {
+            step
+        } else {
+            // On the way back down, which skips the last frame and the
+            // first, since they are shown once each on the way round.
+            self.cycle() - step
+        }
+    }
+
+    // Move the animation on by one step, starting over at the end of the
+    // cycle.
+    fn animate(&mut self) {
+        self.animation += 1;
+        if self.animation >= self.cycle() * self.animation_speed() {
+            self.animation = 0;
+        }
+    }
+
+    // Number of steps before the animation starts over: once through
+    // the frames for Loop, and there and back for PingPong.
+    fn cycle(&self) -> u8 {
+        let num_frames = self.fish_type.frame_count;
+        match self.animation_mode {
+            AnimationMode::Loop => num_frames,
+            AnimationMode::PingPong =>
+                num_frames.saturating_mul(2).saturating_sub(2).max(1),
+        }
+    }
+
+    // The animation speed, limited so that the animation counter can't
+    // overflow.
+    fn animation_speed(&self) -> u8 {
+        self.animation_speed.max(1).min(u8::MAX / self.cycle())
+    }
+
+    pub fn randomize<T: Rng>(&mut self,
+                             screen: &Size,
+                             config: &TankConfig<'_>,
+                             rng: &mut T) {
+        self.animation_speed = pick(&config.animation_speed, rng);
+        self.animation_mode = config.animation_mode;
+        let speed = pick(&config.speed_range, rng);
+        let drift = pick(&config.drift_range, rng);
+        self.depth = pick(&config.depth_range, rng);
+        self.set_scale(Self::depth_scale(self.depth, config));
+
+        let lo: u8 = 0;
+        let hi = self.cycle();
+        self.animation = rng.gen_range(lo, hi * self.animation_speed());
+        self.stride = 0;
+        self.speed = speed;
+        self.turn = 0;
+        let left = match config.direction_bias.get(self.species) {
+            Some(&bias) => rng.gen_range(0, 255) >= bias,
+            None => rng.gen(),
+        };
+        if left {
+            self.direction = Dir::Left;
+            self.velocity = Point::new(-speed, drift);
+        } else {
+            self.direction = Dir::Right;
+            self.velocity = Point::new(speed, drift);
+        }
+        self.heading = self.velocity;
+
+        if self.velocity.x == 0 && self.velocity.y != 0 {
+            // Fish which only swim vertically enter from the top or bottom.
+            self.upper_left.x =
+                random_offset(screen.width, self.size.width, rng);
+            self.upper_left.y = if self.velocity.y > 0 {
+                -cvt(self.size.height)
+            } else {
+                cvt(screen.height)
+            };
+        } else {
+            self.upper_left.x = match self.direction {
+                Dir::Left => cvt(screen.width),
+                Dir::Right => -cvt(self.size.width),
+            };
+            // Keep the fish out of the sand.
+            let water = config.terrain.water_height(screen);
+            self.upper_left.y = random_offset(water, self.size.height, rng);
+        }
+
+        self.golden = config.rare_odds != 0 &&
+            rng.gen_ratio(1, config.rare_odds);
+        if self.golden {
+            self.speed *= 2;
+            self.velocity *= 2;
+            self.heading = self.velocity;
+        }
+
+        self.bob = None;
+        self.hover_frames = 0;
+        self.glide = Point::new(0, 0);
+        self.subpixel = Point::new(0, 0);
+        if config.bobbing {
+            self.bob_phase = rng.gen();
+            self.bob_amplitude = rng.gen_range(BOB_AMPLITUDE.start,
+                                               BOB_AMPLITUDE.end);
+        }
+
+        self.age = 0;
+        self.max_age = pick(&config.lifespan, rng);
+
+        debug_assert!(self.on_screen(screen));
+    }
+
+    // Returns true if the point is within the fish's bounding box.
+    fn covers(&self, pt: &Point) -> bool {
+        let d = *pt - self.upper_left;
+        d.x >= 0 && d.y >= 0 &&
+            d.x < cvt(self.size.width) && d.y < cvt(self.size.height)
+    }
+
+    // Area of the overlap between the two fish's bounding boxes.
+    fn overlap(&self, other: &Fish<'_, FRAMES>) -> u32 {
+        let extent = |a: i32, a_len: u32, b: i32, b_len: u32| {
+            let lo = a.max(b);
+            let hi = (a + cvt(a_len)).min(b + cvt(b_len));
+            to_size(hi - lo)
+        };
+        extent(self.upper_left.x, self.size.width,
+               other.upper_left.x, other.size.width) *
+            extent(self.upper_left.y, self.size.height,
+                   other.upper_left.y, other.size.height)
+    }
+
+    pub fn center(&self) -> Point {
+        self.upper_left + Point::new(cvt(self.size.width) / 2,
+                                     cvt(self.size.height) / 2)
+    }
+
+    // Everything about the fish which affects how it is drawn, so that
+    // we can tell whether it needs to be drawn again.
+    fn appearance(&self) -> (Point, Size, u8, Dir, u8, bool) {
+        (self.upper_left,
+         self.size,
+         self.frame(),
+         self.direction,
+         self.turn,
+         self.flip_v)
+    }
+
+    pub fn behavior(&self) -> Behavior {
+        self.behavior
+    }
+
+    /// Turns the fish upside down, or back the right way up, for a
+    /// sleeping or belly-up fish.  The same sprite is used, mirrored
+    /// top to bottom.
+    pub fn set_flip_v(&mut self, flip_v: bool) {
+        self.flip_v = flip_v;
+    }
+
+    pub fn flip_v(&self) -> bool {
+        self.flip_v
+    }
+
+    // The velocity which heads towards `target`, at up to the fish's
+    // usual speed along each axis.
+    fn chase(&self, target: Point) -> Point {
+        clamp_axes(target - self.center(), self.speed)
+    }
+
+    // Whether the fish has lived out its lifespan, and is on its way off
+    // the screen to be replaced.
+    fn leaving(&self) -> bool {
+        self.max_age != 0 && self.age >= self.max_age
+    }
+
+    // The velocity which takes a fish that is leaving straight off the
+    // side it is facing, however it was going before.
+    fn exit(&self) -> Point {
+        let speed = self.speed.max(1);
+        match self.direction {
+            Dir::Left  => Point::new(-speed, 0),
+            Dir::Right => Point::new(speed, 0),
+        }
+    }
+
+    // The velocity which heads straight away from `threat`, at the
+    // fish's usual speed.
+    fn flee(&self, threat: Point) -> Point {
+        let d = self.center() - threat;
+        // A threat straight above or below doesn't change which way the
+        // fish is going.
+        let dx = if d.x == 0 { self.velocity.x } else { d.x };
+        Point::new(dx.signum() * self.speed, d.y.signum() * self.speed)
+    }
+
+    // The velocity which the other fish in the school are pulling this
+    // fish towards.  All of the arguments are sums over the school.
+    fn flock(&self,
+             config: &TankConfig<'_>,
+             count: i32,
+             centers: Point,
+             velocities: Point,
+             crowding: Point) -> Point {
+        let cohesion = centers / count - self.center();
+        let alignment = velocities / count - self.velocity;
+        let v = self.velocity * 16 +
+            cohesion * config.cohesion +
+            crowding * config.separation +
+            alignment * config.alignment;
+        let v = clamp_axes(div_floor(v + Point::new(8, 8), 16), self.speed);
+        // Keep the fish swimming sideways, which is how it is drawn.
+        let x = match v.x {
+            0 => self.velocity.x.signum(),
+            x => x,
+        };
+        Point::new(x, v.y)
+    }
+
+    // Change the fish's velocity, turning it to face the way it's going.
+    // If `turning` is true, it takes the fish TURN_FRAMES steps to turn.
+    fn steer(&mut self, velocity: Point, turning: bool) {
+        let old_direction = self.direction;
+        self.velocity = velocity;
+        if velocity.x < 0 {
+            self.direction = Dir::Left;
+        } else if velocity.x > 0 {
+            self.direction = Dir::Right;
+        }
+
+        if turning && self.direction != old_direction {
+            self.turn = TURN_FRAMES;
+        }
+    }
+
+    // Turn a freshly randomized fish into a small, slow one in the
+    // distance, swimming straight across from one side.
+    fn make_distant<T: Rng>(&mut self,
+                            screen: &Size,
+                            config: &TankConfig<'_>,
+                            rng: &mut T) {
+        self.set_scale(BG_SCALE);
+        self.golden = false;
+        self.speed = 1;
+        self.velocity = match self.direction {
+            Dir::Left => Point::new(-1, 0),
+            Dir::Right => Point::new(1, 0),
+        };
+        self.heading = self.velocity;
+        self.upper_left.x = match self.direction {
+            Dir::Left => cvt(screen.width),
+            Dir::Right => -cvt(self.size.width),
+        };
+        let water = config.terrain.water_height(screen);
+        self.upper_left.y = random_offset(water, self.size.height, rng);
+    }
+
+    // Sends a fish which has reached an edge of the screen, or the top
+    // of the sand, back the other way, by flipping whichever parts of
+    // its velocity would take it further out.  A fish in a corner flips
+    // both.  A fish which went past the edge, because it was going fast
+    // or the current carried it, is put back inside.
+    fn bounce(&mut self, screen: &Size, config: &TankConfig<'_>) {
+        let right = self.upper_left.x + cvt(self.size.width);
+        let bottom = self.upper_left.y + cvt(self.size.height);
+        let water = cvt(config.terrain.water_height(screen));
+        let mut velocity = self.velocity;
+        let flip_x = (self.upper_left.x <= 0 && velocity.x < 0) ||
+            (right >= cvt(screen.width) && velocity.x > 0);
+        let flip_y = (self.upper_left.y <= 0 && velocity.y < 0) ||
+            (bottom >= water && velocity.y > 0);
+        // The heading is flipped to match, or fish which steer back to
+        // it would turn straight round again.
+        if flip_x {
+            velocity.x = -velocity.x;
+            self.heading.x = self.heading.x.abs() * velocity.x.signum();
+            self.glide.x = -self.glide.x;
+        }
+        if flip_y {
+            velocity.y = -velocity.y;
+            self.heading.y = self.heading.y.abs() * velocity.y.signum();
+            self.glide.y = -self.glide.y;
+        }
+        if flip_x || flip_y {
+            self.steer(velocity, config.turning);
+        }
+
+        // A fish too big for the screen sticks to the top left.
+        let max_x = cvt(screen.width) - cvt(self.size.width);
+        let max_y = water - cvt(self.size.height);
+        self.upper_left.x = self.upper_left.x.min(max_x).max(0);
+        self.upper_left.y = self.upper_left.y.min(max_y).max(0);
+    }
+
+    // Moves a fish which has gone off one edge of the screen to just
+    // off the opposite edge, so that it swims straight back in.
+    fn wrap(&mut self, screen: &Size) {
+        if self.upper_left.x > cvt(screen.width) {
+            self.upper_left.x = -cvt(self.size.width);
+        } else if self.upper_left.x + cvt(self.size.width) < 0 {
+            self.upper_left.x = cvt(screen.width);
+        }
+
+        if self.upper_left.y > cvt(screen.height) {
+            self.upper_left.y = -cvt(self.size.height);
+        } else if self.upper_left.y + cvt(self.size.height) < 0 {
+            self.upper_left.y = cvt(screen.height);
+        }
+    }
+
+    // Moves the fish up or down so that its middle is on a random row
+    // from `rows`, but without putting it in the sand or above the top
+    // of the screen, unless it is too big to fit.
+    fn randomize_rows<T: Rng>(&mut self,
+                              screen: &Size,
+                              config: &TankConfig<'_>,
+                              rows: Range<i32>,
+                              rng: &mut T) {
+        let height = cvt(self.size.height);
+        let lowest = cvt(config.terrain.water_height(screen)) - height;
+        let middle = pick(&rows, rng);
+        self.upper_left.y = (middle - height / 2).min(lowest).max(0);
+    }
+
+    pub fn randomize_x<T: Rng>(&mut self, screen: &Size, rng: &mut T) {
+        self.upper_left.x =
+            random_offset(screen.width, self.size.width, rng);
+    }
+
+    // Moves the fish by `distance`, in 1/SUBPIXELS of a pixel, keeping
+    // whatever is left over for next time.
+    fn move_subpixels(&mut self, distance: Point) {
+        let moved = self.subpixel + distance;
+        let pixels = div_floor(moved, SUBPIXELS);
+        self.upper_left += pixels;
+        self.subpixel = moved - pixels * SUBPIXELS;
+    }
+
+    // Eases the fish's speed towards its velocity by up to
+    // `acceleration`, and moves it by that speed.  The velocity is
+    // scaled by 3/4, so that the fish goes as fast, on average, as it
+    // would moving three steps out of four without acceleration.
+    fn glide_step(&mut self, acceleration: i32) {
+        let mut target = self.velocity * SUBPIXELS * 3 / 4;
+        // A hovering fish still drifts up and down.
+        if self.hover_frames > 0 {
+            target.x = 0;
+        }
+        self.glide += clamp_axes(target - self.glide, acceleration);
+        self.move_subpixels(self.glide);
+    }
+
+    /// Returns true if the fish swam off the screen and was randomized.
+    /// A fish which wraps around or bounces instead returns false.
+    /// `frame` is the number of calls to swim() so far, which bobbing
+    /// fish follow the wave by.
+    pub fn swim<T: Rng>(&mut self,
+                        screen: &Size,
+                        config: &TankConfig<'_>,
+                        frame: u32,
+                        rng: &mut T) -> bool {
+        self.prev_upper_left = self.upper_left;
+        self.prev_size = self.size;
+
+        if self.max_age != 0 {
+            self.age = self.age.saturating_add(1);
+        }
+        let leaving = self.leaving();
+
+        if leaving {
+            // An old fish doesn't stop on its way out.
+            self.hover_frames = 0;
+        } else if self.hover_frames > 0 {
+            self.hover_frames -= 1;
+        } else if config.hover_odds != 0 &&
+            rng.gen_ratio(1, config.hover_odds) {
+            self.hover_frames = rng.gen_range(HOVER_FRAMES.start,
+                                              HOVER_FRAMES.end);
+        }
+
+        if !leaving && config.reverse_odds != 0 && self.on_screen(screen) &&
+            rng.gen_ratio(1, config.reverse_odds) {
+            let back = Point::new(-self.velocity.x, self.velocity.y);
+            self.steer(back, config.turning);
+            // Prey which were fleeing go back to this way afterwards.
+            self.heading.x = -self.heading.x;
+        }
+
+        // How far the fish swims, in 1/SUBPIXELS of a pixel, along
+        // whichever axis it goes further.
+        let moved = |d: Point| d.x.abs().max(d.y.abs());
+        let swum = if config.acceleration > 0 {
+            self.glide_step(config.acceleration);
+            moved(self.glide)
+        } else if rng.gen_ratio(3, 4) {
+            let mut step = self.velocity;
+            // A hovering fish still drifts up and down.
+            if self.hover_frames > 0 {
+                step.x = 0;
+            }
+            self.upper_left += step;
+            moved(step) * SUBPIXELS
+        } else {
+            0
+        };
+
+        if config.current != Point::new(0, 0) {
+            self.move_subpixels(config.current);
+        }
+
+        if config.bobbing {
+            let step = frame / BOB_SPEED + u32::from(self.bob_phase);
+            let idx = size_index(step % 16);
+            let bob = self.bob_amplitude * BOB_WAVE[idx] / 64;
+            // A fish which was just randomized starts wherever it is on
+            // the wave.
+            if let Some(prev) = self.bob {
+                self.upper_left.y += bob - prev;
+            }
+            self.bob = Some(bob);
+        } else if rng.gen_ratio(1, 8) {
+            // Jittering never takes the fish any further past the top of
+            // the screen or the sand than it already is, so that it
+            // doesn't wander off and get randomized while it is still
+            // swimming across.
+            let y = self.upper_left.y;
+            let water = config.terrain.water_height(screen);
+            let lowest = cvt(water) - cvt(self.size.height);
+            self.upper_left.y = (y + rng.gen_range(-1, 2))
+                .max(y.min(0))
+                .min(y.max(lowest));
+        }
+
+        match config.animation_timing {
+            AnimationTiming::Time => self.animate(),
+            AnimationTiming::Distance => {
+                self.stride += swum;
+                while self.stride >= SUBPIXELS {
+                    self.stride -= SUBPIXELS;
+                    self.animate();
+                }
+            }
+        }
+
+        self.turn = self.turn.saturating_sub(1);
+
+        if config.edge_behavior == EdgeBehavior::Bounce && !leaving {
+            self.bounce(screen, config);
+        }
+
+        if self.on_screen(screen) {
+            false
+        } else if config.edge_behavior == EdgeBehavior::Wrap && !leaving {
+            self.wrap(screen);
+            false
+        } else {
+            let phase = (self.animation_speed, self.animation, self.stride);
+            self.randomize(screen, config, rng);
+            if config.keep_animation_phase {
+                // The random picks are still made, so that everything
+                // else is the same as without keep_animation_phase.
+                (self.animation_speed, self.animation, self.stride) = phase;
+            }
+            true
+        }
+    }
+
+    // How far forward the given depth is in depth_range, as the number
+    // of steps from the back and the number of steps from the back to
+    // the front.  None if there is only one depth.
+    fn depth_position(depth: u8, depths: &Range<u8>) -> Option<(u32, u32)> {
+        if depths.start >= depths.end || depths.end - depths.start < 2 {
+            None
+        } else {
+            let span = u32::from(depths.end - depths.start - 1);
+            let t = u32::from(depth.max(depths.start) - depths.start).min(span);
+            Some((t, span))
+        }
+    }
+
+    // The scale of a fish at the given depth.
+    fn depth_scale(depth: u8, config: &TankConfig<'_>) -> u8 {
+        let min_scale = config.min_scale.min(FULL_SCALE);
+        match Self::depth_position(depth, &config.depth_range) {
+            None => FULL_SCALE,
+            Some((t, span)) => {
+                let growth = u32::from(FULL_SCALE - min_scale) * t / span;
+                min_scale + u8::try_from(growth).unwrap()
+            }
+        }
+    }
+
+    // How far a fish at the given depth fades into the water, out of 255.
+    fn depth_haze(depth: u8, config: &TankConfig<'_>) -> u8 {
+        match Self::depth_position(depth, &config.depth_range) {
+            None => 0,
+            Some((t, span)) => {
+                let fade = u32::from(config.max_haze) * (span - t) / span;
+                u8::try_from(fade).unwrap()
+            }
+        }
+    }
+
+    // Change the size the sprite is drawn at, which changes the size of
+    // the fish.
+    fn set_scale(&mut self, scale: u8) {
+        let scale = scale.max(1);
+        let scaled = |length: u32| {
+            let full = u32::from(FULL_SCALE);
+            (length * u32::from(scale) + full - 1) / full
+        };
+        self.scale = scale;
+        self.size = Size::new(scaled(self.fish_type.size.width),
+                              scaled(self.fish_type.size.height));
+    }
+
+    pub fn new<'a>(sprite: Sprite<'a, FRAMES>) -> Fish<'a, FRAMES> {
+        let mut fish = Fish {
+            fish_type:       sprite,
+            upper_left:      Point::new(0, 0),
+            prev_upper_left: Point::new(0, 0),
+            prev_size:       Size::new(0, 0),
+            size:            Size::new(0, 0),
+            direction:       Dir::Right,
+            animation:       0,
+            animation_speed: ANIMATION_SPEED,
+            animation_mode:  AnimationMode::Loop,
+            stride:          0,
+            velocity:        Point::new(1, 0),
+            speed:           1,
+            heading:         Point::new(1, 0),
+            behavior:        Behavior::Drift,
+            species:         0,
+            golden:          false,
+            turn:            0,
+            depth:           0,
+            scale:           FULL_SCALE,
+            bob_phase:       0,
+            bob_amplitude:   0,
+            bob:             None,
+            hover_frames:    0,
+            flip_v:          false,
+            glide:           Point::new(0, 0),
+            subpixel:        Point::new(0, 0),
+            age:             0,
+            max_age:         0,
+        };
+        fish.set_scale(FULL_SCALE);
+        fish
+    }
+}
+
+impl<'a> FishTankBuilder<'a> {
+    /// `sprite_bytes` holds the fish images, in the format of `fish.raw`.
+    pub fn new(sprite_bytes: &'a [u8]) -> FishTankBuilder<'a> {
+        FishTankBuilder {
+            sprite_bytes,
+            sheets: &[],
+            seed:   DEFAULT_SEED,
+            stream: DEFAULT_STREAM,
+            config: TankConfig::default(),
+        }
+    }
+
+    /// Take the sprites from each of `sheets` in turn, instead of the
+    /// sprite data given to new().  See `FishTank::with_sheets`.
+    pub fn sheets(mut self, sheets: &'a [&'a [u8]]) -> Self {
+        self.sheets = sheets;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// The stream of the `Pcg32`, which picks one of 2^63 different
+    /// sequences for each seed.
+    pub fn stream(mut self, stream: u64) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    pub fn fish_count(mut self, count: usize) -> Self {
+        self.config.fish_count = count;
+        self
+    }
+
+    pub fn bubbles(mut self, count: usize) -> Self {
+        self.config.bubble_count = count;
+        self
+    }
+
+    /// The color of the water, in RGB565 format.
+    pub fn background(mut self, color: u16) -> Self {
+        self.config.day_color = color;
+        self.config.depth_color = color;
+        self
+    }
+
+    /// Shade the water from `top` at the surface to `bottom` at the
+    /// bottom of the tank, in RGB565 format.
+    pub fn gradient(mut self, top: u16, bottom: u16) -> Self {
+        self.config.day_color = top;
+        self.config.depth_color = bottom;
+        self
+    }
+
+    /// Draw the water with `tile`, `width` pixels wide, repeated across
+    /// the tank.
+    pub fn tile(mut self, width: u32, tile: &'a [u16]) -> Self {
+        self.config.tile = tile;
+        self.config.tile_width = width;
+        self
+    }
+
+    pub fn speed_range(mut self, range: Range<i32>) -> Self {
+        self.config.speed_range = range;
+        self
+    }
+
+    pub fn seaweed(mut self, seaweed: &'a [Seaweed]) -> Self {
+        self.config.seaweed = seaweed;
+        self
+    }
+
+    pub fn predator(mut self, predator: bool) -> Self {
+        self.config.predator = predator;
+        self
+    }
+
+    pub fn species(mut self, species: &'a [(usize, u32)]) -> Self {
+        self.config.species = species;
+        self
+    }
+
+    /// Make the fish with some sprites more likely to swim one way than
+    /// the other.  See `TankConfig::direction_bias`.
+    pub fn direction_bias(mut self, direction_bias: &'a [u8]) -> Self {
+        self.config.direction_bias = direction_bias;
+        self
+    }
+
+    pub fn orientation(mut self, orientation: Orientation) -> Self {
+        self.config.orientation = orientation;
+        self
+    }
+
+    pub fn upscale(mut self, upscale: u32) -> Self {
+        self.config.upscale = upscale;
+        self
+    }
+
+    pub fn animation_mode(mut self, animation_mode: AnimationMode) -> Self {
+        self.config.animation_mode = animation_mode;
+        self
+    }
+
+    pub fn animation_timing(mut self, timing: AnimationTiming) -> Self {
+        self.config.animation_timing = timing;
+        self
+    }
+
+    pub fn keep_animation_phase(mut self, keep: bool) -> Self {
+        self.config.keep_animation_phase = keep;
+        self
+    }
+
+    pub fn edge_behavior(mut self, edge_behavior: EdgeBehavior) -> Self {
+        self.config.edge_behavior = edge_behavior;
+        self
+    }
+
+    pub fn spawn(mut self, spawn: SpawnStrategy) -> Self {
+        self.config.spawn = spawn;
+        self
+    }
+
+    pub fn caustics(mut self, caustics: bool) -> Self {
+        self.config.caustics = caustics;
+        self
+    }
+
+    pub fn bubbles_in_front(mut self, in_front: bool) -> Self {
+        self.config.bubbles_in_front = in_front;
+        self
+    }
+
+    pub fn bobbing(mut self, bobbing: bool) -> Self {
+        self.config.bobbing = bobbing;
+        self
+    }
+
+    pub fn hover_odds(mut self, odds: u32) -> Self {
+        self.config.hover_odds = odds;
+        self
+    }
+
+    pub fn reverse_odds(mut self, odds: u32) -> Self {
+        self.config.reverse_odds = odds;
+        self
+    }
+
+    pub fn lifespan(mut self, lifespan: Range<u32>) -> Self {
+        self.config.lifespan = lifespan;
+        self
+    }
+
+    pub fn acceleration(mut self, acceleration: i32) -> Self {
+        self.config.acceleration = acceleration;
+        self
+    }
+
+    pub fn current(mut self, current: Point) -> Self {
+        self.config.current = current;
+        self
+    }
+
+    pub fn avoid_collisions(mut self, avoid: bool) -> Self {
+        self.config.avoid_collisions = avoid;
+        self
+    }
+
+    pub fn background_fish(mut self, count: usize) -> Self {
+        self.config.background_fish_count = count;
+        self
+    }
+
+    /// Start with an empty tank, `empty_color`, which fills with water.
+    pub fn intro(mut self, empty_color: u16) -> Self {
+        self.config.intro = Some(empty_color);
+        self
+    }
+
+    pub fn debug_boxes(mut self, debug_boxes: bool) -> Self {
+        self.config.debug_boxes = debug_boxes;
+        self
+    }
+
+    /// `sand_height` rows of sand along the bottom, with `rocks` on it.
+    pub fn terrain(mut self, sand_height: u32, rocks: &'a [Rock]) -> Self {
+        self.config.terrain = Terrain::new(sand_height, rocks);
+        self
+    }
+
+    /// Replace all of the options with `config`.
+    pub fn config(mut self, config: TankConfig<'a>) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn build(self, screen_size: Size)
+                 -> Result<DefaultFishTank<'a>, TankError> {
+        let rng = seeded_rng(self.seed, self.stream);
+        if self.sheets.is_empty() {
+            FishTank::with_rng(screen_size, self.sprite_bytes, rng, self.config)
+        } else {
+            FishTank::with_sheets(screen_size, self.sheets, rng, self.config)
+        }
+    }
+}
+
+impl Default for TankConfig<'_> {
+    fn default() -> Self {
+        TankConfig {
+            fish_count:      NUM_FISH,
+            animation_speed: ANIMATION_SPEED..ANIMATION_SPEED + 1,
+            animation_mode:  AnimationMode::Loop,
+            animation_timing: AnimationTiming::Time,
+            keep_animation_phase: false,
+            speed_range:     1..2,
+            drift_range:     0..1,
+            depth_range:     0..1,
+            min_scale:       FULL_SCALE,
+            max_haze:        0,
+            bubble_count:    0,
+            seaweed:         &[],
+            day_length:      0,
+            day_color:       BACKGROUND,
+            night_color:     NIGHT_BACKGROUND,
+            depth_color:     BACKGROUND,
+            tile:            &[],
+            tile_width:      0,
+            predator:        false,
+            flee_radius:     24,
+            flocking:        false,
+            flock_radius:    32,
+            cohesion:        COHESION,
+            separation:      SEPARATION,
+            alignment:       ALIGNMENT,
+            species:         &[],
+            direction_bias:  &[],
+            sprite_area:     0..u32::MAX,
+            rare_odds:       0,
+            turning:         false,
+            food_radius:     40,
+            caustics:        false,
+            terrain:         Terrain::new(0, &[]),
+            background_fish_count: 0,
+            avoid_collisions: false,
+            bobbing:         false,
+            hover_odds:      0,
+            reverse_odds:    0,
+            lifespan:        0..0,
+            acceleration:    0,
+            current:         Point::new(0, 0),
+            bubbles_in_front: false,
+            intro:           None,
+            debug_boxes:     false,
+            orientation:     Orientation::Landscape,
+            upscale:         1,
+            edge_behavior:   EdgeBehavior::Randomize,
+            spawn:           SpawnStrategy::Random,
+        }
+    }
+}
+
+impl Orientation {
+    // Size of the tank when it is drawn on a display of the given size.
+    fn tank_size(self, display: Size) -> Size {
+        match self {
+            Orientation::Landscape | Orientation::LandscapeFlipped => display,
+            Orientation::Portrait | Orientation::PortraitFlipped =>
+                Size::new(display.height, display.width),
+        }
+    }
+
+    // Where the point pt in the tank is drawn on the display.
+    fn to_display(self, pt: Point, display: Size) -> Point {
+        let w = cvt(display.width);
+        let h = cvt(display.height);
+        match self {
+            Orientation::Landscape        => pt,
+            Orientation::Portrait         => Point::new(w - 1 - pt.y, pt.x),
+            Orientation::LandscapeFlipped => Point::new(w - 1 - pt.x,
+                                                        h - 1 - pt.y),
+            Orientation::PortraitFlipped  => Point::new(pt.y, h - 1 - pt.x),
+        }
+    }
+
+    // The point in the tank which is drawn at pt on the display.
+    fn to_tank(self, pt: Point, display: Size) -> Point {
+        let w = cvt(display.width);
+        let h = cvt(display.height);
+        match self {
+            Orientation::Landscape        => pt,
+            Orientation::Portrait         => Point::new(pt.y, w - 1 - pt.x),
+            Orientation::LandscapeFlipped => Point::new(w - 1 - pt.x,
+                                                        h - 1 - pt.y),
+            Orientation::PortraitFlipped  => Point::new(h - 1 - pt.y, pt.x),
+        }
+    }
+
+    // The area of the display covered by rect in the tank.
+    fn rect_to_display(self, rect: Rectangle, display: Size) -> Rectangle {
+        let a = self.to_display(rect.top_left, display);
+        let b = self.to_display(rect.bottom_right, display);
+        Rectangle::new(Point::new(a.x.min(b.x), a.y.min(b.y)),
+                       Point::new(a.x.max(b.x), a.y.max(b.y)))
+    }
+}
+
+impl Seaweed {
+    /// A strand of seaweed `height` pixels tall, whose bottom is at
+    /// `base`.  Strands with different phases sway differently.
+    pub fn new(base: Point, height: u32, phase: u8) -> Seaweed {
+        Seaweed { base, height, phase }
+    }
+
+    /// Returns true if the point is on the strand at the given frame.
+    pub fn get_point(&self, pt: &Point, frame: u32) -> bool {
+        let row = self.base.y - pt.y;
+        if row < 0 || row >= cvt(self.height) {
+            false
+        } else {
+            let row = to_size(row);
+            let sway = row.wrapping_add(self.phase.into())
+                .wrapping_add(frame / SWAY_SPEED);
+            let idx = size_index(sway % 8);
+            let left = self.base.x + SWAY[idx];
+            pt.x >= left && pt.x < left + SEAWEED_WIDTH
+        }
+    }
+
+    /// The area the strand can occupy while swaying, clipped to
+    /// the screen.
+    pub fn bounds(&self, screen: &Size) -> Option<Rectangle> {
+        let amplitude = SWAY[2];
+        let width = to_size(SEAWEED_WIDTH + 2 * amplitude);
+        let upper_left = Point::new(self.base.x - amplitude,
+                                    self.base.y - cvt(self.height) + 1);
+        let size = Size::new(width, self.height);
+        union_rect(upper_left, size, upper_left, size, screen)
+    }
+}
+
+impl Rock {
+    /// A rock `radius` pixels in radius, centered on `center`.
+    pub fn new(center: Point, radius: u32) -> Rock {
+        Rock { center, radius: cvt(radius) }
+    }
+
+    /// Returns true if the point is on the rock.
+    pub fn get_point(&self, pt: &Point) -> bool {
+        let dx = pt.x - self.center.x;
+        let dy = pt.y - self.center.y;
+        dx * dx + dy * dy <= self.radius * self.radius
+    }
+}
+
+impl<'a> Terrain<'a> {
+    /// `sand_height` rows of sand along the bottom of the tank, with
+    /// `rocks` in front of the sand.
+    pub fn new(sand_height: u32, rocks: &'a [Rock]) -> Terrain<'a> {
+        Terrain { sand_height, rocks }
+    }
+
+    pub fn sand_height(&self) -> u32 {
+        self.sand_height
+    }
+
+    pub fn rocks(&self) -> &'a [Rock] {
+        self.rocks
+    }
+
+    /// The color of the terrain at the point, or None if the point is
+    /// open water.
+    pub fn get_point(&self, pt: &Point, screen: &Size) -> Option<u16> {
+        if self.rocks.iter().any(|rock| rock.get_point(pt)) {
+            Some(ROCK_COLOR)
+        } else if pt.y >= cvt(screen.height) - cvt(self.sand_height) &&
+            pt.y < cvt(screen.height) {
+            Some(SAND_COLOR)
+        } else {
+            None
+        }
+    }
+
+    // Height of the open water above the sand.
+    fn water_height(&self, screen: &Size) -> u32 {
+        screen.height.saturating_sub(self.sand_height)
+    }
+}
+
+impl Bubble {
+    /// Returns true if the point is on the bubble's ring.
+    pub fn get_point(&self, pt: &Point) -> bool {
+        let dx = pt.x - self.center.x;
+        let dy = pt.y - self.center.y;
+        let d2 = dx * dx + dy * dy;
+        d2 <= self.radius * self.radius &&
+            d2 > (self.radius - 1) * (self.radius - 1)
+    }
+
+    fn upper_left(center: Point, radius: i32) -> Point {
+        Point::new(center.x - radius, center.y - radius)
+    }
+
+    /// The union of the bubble's previous and current bounding boxes,
+    /// clipped to the screen.  Since the radius changes when a bubble
+    /// is recycled, the boxes are big enough for the largest bubble.
+    pub fn dirty_rect(&self, screen: &Size) -> Option<Rectangle> {
+        let radius = BUBBLE_RADIUS.end - 1;
+        let diameter = to_size(2 * radius + 1);
+        let size = Size::new(diameter, diameter);
+        union_rect(Bubble::upper_left(self.prev_center, radius), size,
+                   Bubble::upper_left(self.center, radius), size,
+                   screen)
+    }
+
+    /// Start the bubble somewhere below the bottom of the screen, so
+    /// that it appears after a random delay.
+    pub fn randomize<T: Rng>(&mut self, screen: &Size, rng: &mut T) {
+        self.radius = rng.gen_range(BUBBLE_RADIUS.start, BUBBLE_RADIUS.end);
+        self.rise = rng.gen_range(1, 3);
+        self.center.x = random_offset(screen.width, 0, rng);
+        self.center.y = cvt(screen.height) + self.radius +
+            random_offset(screen.height, 0, rng);
+    }
+
+    /// Move the bubble to a random height on the screen.
+    pub fn randomize_y<T: Rng>(&mut self, screen: &Size, rng: &mut T) {
+        self.center.y = random_offset(screen.height, 0, rng);
+    }
+
+    pub fn swim<T: Rng>(&mut self, screen: &Size, rng: &mut T) {
+        self.prev_center = self.center;
+
+        self.center.y -= self.rise;
+        if rng.gen_ratio(1, 4) {
+            self.center.x += rng.gen_range(-1, 2);
+            self.center.x = self.center.x.max(0).min(cvt(screen.width) - 1);
+        }
+
+        if self.center.y + self.radius < 0 {
+            self.randomize(screen, rng);
+        }
+    }
+
+    pub fn new() -> Bubble {
+        Bubble {
+            center:      Point::new(0, 0),
+            prev_center: Point::new(0, 0),
+            radius:      1,
+            rise:        1,
+        }
+    }
+}
+
+impl Default for Bubble {
+    fn default() -> Bubble {
+        Bubble::new()
+    }
+}
+
+impl Pellet {
+    fn new(center: Point) -> Pellet {
+        let upper_left = center - Point::new(FOOD_SIZE / 2, FOOD_SIZE / 2);
+        Pellet {
+            upper_left,
+            prev_upper_left: upper_left,
+        }
+    }
+
+    fn center(&self) -> Point {
+        self.upper_left + Point::new(FOOD_SIZE / 2, FOOD_SIZE / 2)
+    }
+
+    // Returns true if the point is on the pellet.
+    fn get_point(&self, pt: &Point) -> bool {
+        let d = *pt - self.upper_left;
+        d.x >= 0 && d.y >= 0 && d.x < FOOD_SIZE && d.y < FOOD_SIZE
+    }
+
+    // The union of the pellet's previous and current bounding boxes,
+    // clipped to the screen.
+    fn dirty_rect(&self, screen: &Size) -> Option<Rectangle> {
+        let side = to_size(FOOD_SIZE);
+        let size = Size::new(side, side);
+        union_rect(self.prev_upper_left, size, self.upper_left, size, screen)
+    }
+
+    fn sink(&mut self, frame: u32) {
+        self.prev_upper_left = self.upper_left;
+        if frame % FOOD_SINK_SPEED == 0 {
+            self.upper_left.y += 1;
+        }
+    }
+}
+
+impl DefaultFishTank<'_> {
+    /// `sprite_bytes` holds the fish images, in the format of `fish.raw`.
+    /// It is read as u16 words, so it must be 2-byte aligned and an even
+    /// number of bytes long, or else `TankError::BadSpriteData` is
+    /// returned.
+    pub fn new<'a>(screen_size: Size,
+                   sprite_bytes: &'a [u8],
+                   seed: u64) -> Result<DefaultFishTank<'a>, TankError> {
+        FishTank::with_config(screen_size, sprite_bytes, seed,
+                              TankConfig::default())
+    }
+
+    pub fn with_config<'a>(screen_size: Size,
+                           sprite_bytes: &'a [u8],
+                           seed: u64,
+                           config: TankConfig<'a>)
+                           -> Result<DefaultFishTank<'a>, TankError> {
+        FishTank::with_rng(screen_size, sprite_bytes,
+                           seeded_rng(seed, DEFAULT_STREAM), config)
+    }
+
+    /// Start the tank over, just as if it had been built again with
+    /// `seed`.  See `reset_with_rng`.
+    pub fn reset(&mut self, seed: u64) {
+        self.reset_with_rng(seeded_rng(seed, DEFAULT_STREAM));
+    }
+
+    /// Like `reset`, but for a tank built with `FishTankBuilder::stream`.
+    pub fn reset_with_stream(&mut self, seed: u64, stream: u64) {
+        self.reset_with_rng(seeded_rng(seed, stream));
+    }
+
+    /// Like `new`, but with the mix of species given by `species` (see
+    /// `TankConfig::species`).
+    pub fn with_species<'a>(screen_size: Size,
+                            sprite_bytes: &'a [u8],
+                            seed: u64,
+                            species: &'a [(usize, u32)])
+                            -> Result<DefaultFishTank<'a>, TankError> {
+        let config = TankConfig { species, ..TankConfig::default() };
+        FishTank::with_config(screen_size, sprite_bytes, seed, config)
+    }
+}
+
+impl<R: Rng> FishTank<'_, R> {
+    /// Like `with_config`, but draws its random numbers from `rng`
+    /// instead of seeding a `Pcg32`.
+    pub fn with_rng<'a>(screen_size: Size,
+                        sprite_bytes: &'a [u8],
+                        rng: R,
+                        config: TankConfig<'a>) -> Result<FishTank<'a, R>,
+                                                          TankError> {
+        FishTank::with_frames(screen_size, sprite_bytes, rng, config)
+    }
+}
+
+impl<R: Rng, const FRAMES: usize> FishTank<'_, R, FRAMES> {
+    /// Like `with_rng`, but for sprite data with `FRAMES` frames of
+    /// animation per sprite, instead of `NUM_FRAMES`.  The frame count
+    /// usually has to be given, as in `FishTank::<Pcg32, 4>::with_frames`.
+    pub fn with_frames<'a>(screen_size: Size,
+                           sprite_bytes: &'a [u8],
+                           rng: R,
+                           config: TankConfig<'a>)
+                           -> Result<FishTank<'a, R, FRAMES>, TankError> {
+        FishTank::with_sheets(screen_size, &[sprite_bytes], rng, config)
+    }
+
+    /// Like `with_frames`, but with the sprites of several sprite
+    /// sheets, each in the format of `fish.raw`.  The sprites are
+    /// numbered through the first sheet, then the second, and so on,
+    /// up to NUM_SPRITES sprites in all.  Any more are left out.
+    pub fn with_sheets<'a>(screen_size: Size,
+                           sheets: &[&'a [u8]],
+                           rng: R,
+                           config: TankConfig<'a>)
+                           -> Result<FishTank<'a, R, FRAMES>, TankError> {
+        let first = *sheets.first().ok_or(TankError::BadSpriteData)?;
+        let first = first.as_slice_of::<u16>()
+            .map_err(|_| TankError::BadSpriteData)?;
+
+        // Load every sprite up front, so that adding a fish or resetting
+        // the tank later can't fail.
+        let dummy_sprite = Sprite::make_sprite(0, first)?;
+        let mut sprites = [dummy_sprite; NUM_SPRITES];
+        let mut count = 0;
+        for &sheet in sheets {
+            let sprite_data = sheet.as_slice_of::<u16>()
+                .map_err(|_| TankError::BadSpriteData)?;
+            let in_sheet = sprite_count(sprite_data).min(NUM_SPRITES - count);
+            for n in 0..in_sheet {
+                sprites[count] = Sprite::make_sprite(n, sprite_data)?;
+                count += 1;
+            }
+        }
+
+        if config.predator && PREDATOR_SPRITE >= count {
+            return Err(TankError::NoSuchSprite);
+        }
+
+        let mut total: u32 = 0;
+        for &(sprite_num, weight) in config.species {
+            if sprite_num >= count {
+                return Err(TankError::NoSuchSprite);
+            }
+            total = total.checked_add(weight)
+                .ok_or(TankError::BadWeights)?;
+        }
+        if !config.species.is_empty() && total == 0 {
+            return Err(TankError::BadWeights);
+        }
+
+        let fits = |n: usize| config.sprite_area.contains(&sprites[n].area());
+        let usable = if config.species.is_empty() {
+            (0..count).any(fits)
+        } else {
+            config.species.iter().any(|&(n, weight)| weight > 0 && fits(n))
+        };
+        if !usable {
+            return Err(TankError::NoSpriteInRange);
+        }
+
+        let upscale = config.upscale.max(1);
+        let scaled_size = Size::new((screen_size.width / upscale).max(1),
+                                    (screen_size.height / upscale).max(1));
+
+        let mut tank = FishTank {
+            sprites,
+            sprite_count: count,
+            fish:    [Fish::new(dummy_sprite); MAX_FISH],
+            num_fish: 0,
+            bg_fish: [Fish::new(dummy_sprite); MAX_BG_FISH],
+            num_bg_fish: 0,
+            order:   [0;                       MAX_FISH],
+            bands:   [0;                       NUM_BANDS],
+            bubbles: [Bubble::new();           MAX_BUBBLES],
+            num_bubbles: 0,
+            food:    [Pellet::new(Point::new(0, 0)); MAX_FOOD],
+            num_food: 0,
+            size:    config.orientation.tank_size(scaled_size),
+            display_size: screen_size,
+            scaled_size,
+            rng,
+            config,
+            frame_counter: 0,
+            vacated: None,
+            erasing: None,
+            paused:  false,
+            brightness: u8::MAX,
+            intro:   0,
+            mouths_opened: 0,
+            overlay: None,
+            #[cfg(test)]
+            visits:  Default::default(),
+        };
+
+        tank.populate();
+
+        Ok(tank)
+    }
+
+    /// A snapshot of everything about the tank which changes as it runs,
+    /// random number generator included.
+    pub fn save_state(&self) -> TankState<R> where R: Clone {
+        TankState {
+            fish:          self.fish_states(&self.fish),
+            num_fish:      self.num_fish,
+            bg_fish:       self.fish_states(&self.bg_fish),
+            num_bg_fish:   self.num_bg_fish,
+            bubbles:       self.bubbles.map(|b| BubbleState {
+                center:      point_state(b.center),
+                prev_center: point_state(b.prev_center),
+                radius:      b.radius,
+                rise:        b.rise,
+            }),
+            num_bubbles:   self.num_bubbles,
+            food:          self.food.map(|p| PelletState {
+                upper_left:      point_state(p.upper_left),
+                prev_upper_left: point_state(p.prev_upper_left),
+            }),
+            num_food:      self.num_food,
+            frame_counter: self.frame_counter,
+            vacated:       self.vacated.map(rect_state),
+            erasing:       self.erasing.map(rect_state),
+            paused:        self.paused,
+            brightness:    self.brightness,
+            intro:         self.intro,
+            rng:           self.rng.clone(),
+        }
+    }
+
+    /// Put the tank back the way it was when `state` was saved.  The
+    /// screen should be redrawn with render_all() afterwards, as for
+    /// `reset_with_rng`.
+    pub fn restore_state(&mut self, state: &TankState<R>)
+                         -> Result<(), TankError>
+    where R: Clone,
+    {
+        let sprite_ok = |f: &FishState| f.sprite < self.sprite_count;
+        if state.num_fish > MAX_FISH || state.num_bg_fish > MAX_BG_FISH ||
+            state.num_bubbles > MAX_BUBBLES || state.num_food > MAX_FOOD ||
+            !state.fish.iter().all(sprite_ok) ||
+            !state.bg_fish.iter().all(sprite_ok) {
+            return Err(TankError::BadState);
+        }
+
+        for (fish, f) in self.fish.iter_mut().zip(&state.fish) {
+            *fish = Fish::from_state(self.sprites[f.sprite], f);
+        }
+        for (fish, f) in self.bg_fish.iter_mut().zip(&state.bg_fish) {
+            *fish = Fish::from_state(self.sprites[f.sprite], f);
+        }
+        for (bubble, b) in self.bubbles.iter_mut().zip(&state.bubbles) {
+            bubble.center      = state_point(b.center);
+            bubble.prev_center = state_point(b.prev_center);
+            bubble.radius      = b.radius;
+            bubble.rise        = b.rise;
+        }
+        for (pellet, p) in self.food.iter_mut().zip(&state.food) {
+            pellet.upper_left      = state_point(p.upper_left);
+            pellet.prev_upper_left = state_point(p.prev_upper_left);
+        }
+
+        self.num_fish = state.num_fish;
+        self.num_bg_fish = state.num_bg_fish;
+        self.num_bubbles = state.num_bubbles;
+        self.num_food = state.num_food;
+        self.frame_counter = state.frame_counter;
+        self.vacated = state.vacated.map(state_rect);
+        self.erasing = state.erasing.map(state_rect);
+        self.paused = state.paused;
+        self.brightness = state.brightness;
+        self.intro = state.intro;
+        self.mouths_opened = 0;
+        self.rng = state.rng.clone();
+        self.sort_by_depth();
+        self.update_bands();
+        Ok(())
+    }
+
+    fn fish_states<const N: usize>(&self, fish: &[Fish<'_, FRAMES>; N])
+                                   -> [FishState; N] {
+        let mut states = [fish[0].state(); N];
+        for (state, f) in states.iter_mut().zip(fish) {
+            *state = f.state();
+        }
+        states
+    }
+
+    /// Start the tank over with a new random number generator.  The tank
+    /// ends up just the same as a new tank built with `rng` and the same
+    /// options would be, so the screen should be cleared to
+    /// `background_color()` and drawn with render_all() afterwards.
+    pub fn reset_with_rng(&mut self, rng: R) {
+        self.rng = rng;
+        self.populate();
+    }
+
+    // Fill the tank with fish and bubbles, as when it is first built.
+    // Moves each fish into a band of the water of its own, shuffling
+    // which fish goes in which band so that the species don't end up in
+    // order from top to bottom.
+    fn stratify(&mut self) {
+        let mut bands = [0; MAX_FISH];
+        for (i, band) in bands.iter_mut().enumerate() {
+            *band = i;
+        }
+        for i in (1..self.num_fish).rev() {
+            bands.swap(i, self.rng.gen_range(0, i + 1));
+        }
+
+        let water = self.config.terrain.water_height(&self.size);
+        let count: u32 = self.num_fish.try_into().unwrap();
+        let row = |band: usize| {
+            let band: u32 = band.try_into().unwrap();
+            cvt(water * band / count)
+        };
+        for (fish, &band) in self.fish.iter_mut().zip(&bands)
+            .take(self.num_fish) {
+            fish.randomize_rows(&self.size, &self.config,
+                                row(band)..row(band + 1), &mut self.rng);
+        }
+    }
+
+    fn populate(&mut self) {
+        self.num_fish = self.config.fish_count.min(MAX_FISH);
+        self.num_bubbles = self.config.bubble_count.min(MAX_BUBBLES);
+        self.num_bg_fish = self.config.background_fish_count.min(MAX_BG_FISH);
+        self.num_food = 0;
+        self.frame_counter = 0;
+        self.vacated = None;
+        self.erasing = None;
+        self.paused = false;
+        self.intro = 0;
+        self.mouths_opened = 0;
+
+        for i in 0..MAX_FISH {
+            let sprite_num = if self.config.species.is_empty() {
+                let count = self.fitting_sprites().count();
+                self.fitting_sprites().nth(i % count).unwrap()
+            } else {
+                self.pick_species()
+            };
+            self.fish[i] = Fish::new(self.sprites[sprite_num]);
+            self.fish[i].species = sprite_num;
+        }
+
+        if self.config.predator {
+            self.fish[0] = Fish::new(self.sprites[PREDATOR_SPRITE]);
+            self.fish[0].species = PREDATOR_SPRITE;
+            self.fish[0].behavior = Behavior::Predator;
+            for fish in &mut self.fish[1..] {
+                fish.behavior = Behavior::Prey;
+            }
+        }
+
+        for i in 0..self.num_fish {
+            self.fish[i].randomize  (&self.size, &self.config, &mut self.rng);
+            self.fish[i].randomize_x(&self.size, &mut self.rng);
+        }
+
+        if self.config.spawn == SpawnStrategy::Stratified {
+            self.stratify();
+        }
+
+        self.sort_by_depth();
+
+        for i in 0..self.num_bg_fish {
+            let sprite_num = self.random_sprite();
+            let fish = &mut self.bg_fish[i];
+            *fish = Fish::new(self.sprites[sprite_num]);
+            fish.species = sprite_num;
+            fish.randomize   (&self.size, &self.config, &mut self.rng);
+            fish.make_distant(&self.size, &self.config, &mut self.rng);
+            fish.randomize_x (&self.size, &mut self.rng);
+        }
+
+        for i in 0..self.num_bubbles {
+            self.bubbles[i] = Bubble::new();
+            self.bubbles[i].randomize  (&self.size, &mut self.rng);
+            self.bubbles[i].randomize_y(&self.size, &mut self.rng);
+        }
+
+        self.update_bands();
+    }
+
+    // Whether sprite n is within sprite_area, so can be given to fish.
+    fn sprite_fits(&self, n: usize) -> bool {
+        self.config.sprite_area.contains(&self.sprites[n].area())
+    }
+
+    // The numbers of the sprites which are within sprite_area, in order.
+    fn fitting_sprites(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.sprite_count).filter(move |&n| self.sprite_fits(n))
+    }
+
+    // One of the sprites within sprite_area, picked at random.
+    fn random_sprite(&mut self) -> usize {
+        let count = self.fitting_sprites().count();
+        let n = self.rng.gen_range(0, count);
+        self.fitting_sprites().nth(n).unwrap()
+    }
+
+    // Pick a sprite at random, according to the weights in the config's
+    // species, which must not be empty.
+    fn pick_species(&mut self) -> usize {
+        let species = self.config.species;
+        let total: u32 = species.iter()
+            .filter(|&&(sprite_num, _)| self.sprite_fits(sprite_num))
+            .map(|&(_, weight)| weight)
+            .sum();
+        let mut n = self.rng.gen_range(0, total);
+        for &(sprite_num, weight) in species {
+            if !self.sprite_fits(sprite_num) {
+                continue;
+            }
+            if n < weight {
+                return sprite_num;
+            }
+            n -= weight;
+        }
+        species[0].0
+    }
+
+    /// Give each fish in the tank a new sprite, picked at random
+    /// according to the configured species weights, or from all the
+    /// sprites if there aren't any.  The fish stay where they are, and
+    /// keep swimming the same way.  The predator, if there is one, stays
+    /// the predator.
+    pub fn reshuffle_species(&mut self) {
+        for i in 0..self.num_fish {
+            if self.fish[i].behavior == Behavior::Predator {
+                continue;
+            }
+
+            let sprite_num = if self.config.species.is_empty() {
+                self.random_sprite()
+            } else {
+                self.pick_species()
+            };
+            // The new sprite may be smaller, so the old one needs erasing.
+            let rect = self.fish[i].dirty_rect(&self.size);
+            self.vacated = merge_rects(self.vacated, rect);
+
+            let fish = &mut self.fish[i];
+            fish.fish_type = self.sprites[sprite_num];
+            fish.species = sprite_num;
+            fish.set_scale(fish.scale);
+            if fish.animation >= fish.cycle() * fish.animation_speed() {
+                fish.animation = 0;
+            }
+        }
+
+        self.update_bands();
+    }
+
+    // Which band a row of the screen is in.  y must be on the screen.
+    fn band(&self, y: i32) -> usize {
+        let y = to_index(y);
+        let height = size_index(self.size.height).max(1);
+        y.saturating_mul(NUM_BANDS) / height
+    }
+
+    // Recompute which fish overlap each band.  This needs to happen
+    // whenever the fish move or are reordered.
+    fn update_bands(&mut self) {
+        self.bands = [0; NUM_BANDS];
+        let bottom = cvt(self.size.height) - 1;
+        for (n, &i) in self.order[..self.num_fish].iter().enumerate() {
+            let fish = &self.fish[i];
+            let top = fish.upper_left.y;
+            let end = top + cvt(fish.size.height) - 1;
+            if end < 0 || top > bottom {
+                continue;
+            }
+
+            for b in self.band(top.max(0))..=self.band(end.min(bottom)) {
+                self.bands[b] |= 1 << n;
+            }
+        }
+    }
+
+    // Sort the fish from front to back.  This only needs to happen when
+    // a fish's depth changes, which is when it is randomized.
+    fn sort_by_depth(&mut self) {
+        let fish = &self.fish;
+        let order = &mut self.order[..self.num_fish];
+        for (i, idx) in order.iter_mut().enumerate() {
+            *idx = i;
+        }
+        order.sort_unstable_by_key(|&i| (u8::MAX - fish[i].depth, i));
+    }
+
+    /// Add a fish, which swims in from the edge of the screen.  Returns
+    /// false, without adding anything, if there are already MAX_FISH
+    /// fish.
+    pub fn add_fish(&mut self) -> bool {
+        if self.num_fish >= MAX_FISH {
+            return false;
+        }
+
+        let fish = &mut self.fish[self.num_fish];
+        let behavior = fish.behavior;
+        let species = fish.species;
+        *fish = Fish::new(fish.fish_type);
+        fish.behavior = behavior;
+        fish.species = species;
+        fish.randomize(&self.size, &self.config, &mut self.rng);
+        fish.prev_upper_left = fish.upper_left;
+        fish.prev_size = fish.size;
+        self.num_fish += 1;
+        self.sort_by_depth();
+        self.update_bands();
+        true
+    }
+
+    /// Remove the most recently added fish.  Returns false if there were
+    /// no fish to remove.
+    pub fn remove_fish(&mut self) -> bool {
+        if self.num_fish == 0 {
+            return false;
+        }
+
+        self.num_fish -= 1;
+        let rect = self.fish[self.num_fish].dirty_rect(&self.size);
+        self.vacated = merge_rects(self.vacated, rect);
+        self.sort_by_depth();
+        self.update_bands();
+        true
+    }
+
+    /// Turn fish number `index` upside down, or back the right way up,
+    /// as with `Fish::set_flip_v`.  Returns false, without changing
+    /// anything, if there is no such fish.
+    pub fn set_flip_v(&mut self, index: usize, flip_v: bool) -> bool {
+        if index >= self.num_fish {
+            return false;
+        }
+
+        let fish = &mut self.fish[index];
+        fish.set_flip_v(flip_v);
+        // Make sure the fish gets redrawn, even if it doesn't move.
+        self.vacated = merge_rects(self.vacated, fish.dirty_rect(&self.size));
+        true
+    }
+
+    /// Drop a food pellet into the tank, centered on `at` in the tank's
+    /// coordinates.  It sinks until a fish eats it, or it reaches the
+    /// bottom.  Returns false, without dropping anything, if `at` is off
+    /// the screen or there are already `MAX_FOOD` pellets.
+    pub fn drop_food(&mut self, at: Point) -> bool {
+        let on_screen = at.x >= 0 && at.y >= 0 &&
+            at.x < cvt(self.size.width) && at.y < cvt(self.size.height);
+        if !on_screen || self.num_food >= MAX_FOOD {
+            return false;
+        }
+
+        let pellet = Pellet::new(at);
+        // Make sure the pellet gets drawn, even if nothing else changes.
+        self.vacated = merge_rects(self.vacated, pellet.dirty_rect(&self.size));
+        self.food[self.num_food] = pellet;
+        self.num_food += 1;
+        true
+    }
+
+    /// Freeze the tank, or start it going again.  While the tank is
+    /// paused, swim() doesn't move or animate anything, and step()
+    /// returns false unless fish were removed or food was dropped.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Change the `current` the fish are carried along by, for example
+    /// to make it ebb and flow.  Like the rest of the `TankConfig`, it
+    /// isn't included in `save_state()`.
+    pub fn set_current(&mut self, current: Point) {
+        self.config.current = current;
+    }
+
+    pub fn current(&self) -> Point {
+        self.config.current
+    }
+
+    /// Take away the overlay, if there is one.
+    pub fn clear_overlay(&mut self) {
+        self.vacated = merge_rects(self.vacated, self.overlay_rect());
+        self.overlay = None;
+    }
+
+    // The area of the screen covered by the overlay, if any.
+    fn overlay_rect(&self) -> Option<Rectangle> {
+        let (sprite, at) = self.overlay?;
+        union_rect(at, sprite.size, at, sprite.size, &self.size)
+    }
+
+    /// Dim everything drawn, fish and water alike, towards black.  255
+    /// is full brightness and 0 is black.  The whole screen is redrawn
+    /// on the next frame.
+    pub fn set_brightness(&mut self, brightness: u8) {
+        if brightness != self.brightness {
+            self.brightness = brightness;
+            self.vacated = merge_rects(self.vacated, self.screen_rect());
+        }
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    /// Move everything in the tank on by one frame.  Each frame, call
+    /// swim() or step() first, and then render() or one of the other
+    /// render methods, which draw the tank as it was left by the last
+    /// call to swim().  Anything which has to keep in time with the
+    /// picture, such as reading events(), goes in between, or can be
+    /// handed to step_and_render().
+    pub fn swim(&mut self) {
+        self.step();
+    }
+
+    /// Fill the tank with a little more water, if it was configured with
+    /// an `intro`.  Call this instead of swim() until it returns false,
+    /// drawing the tank after each call which returns true.  Without an
+    /// intro, it always returns false.
+    pub fn intro_frame(&mut self) -> bool {
+        if !self.intro_running() {
+            return false;
+        }
+
+        self.intro += 1;
+        self.intro <= INTRO_FRAMES
+    }
+
+    // Whether the tank is still filling with water, or has only just
+    // filled up and not been drawn full yet.
+    fn intro_running(&self) -> bool {
+        self.config.intro.is_some() && self.intro <= INTRO_FRAMES
+    }
+
+    // The color of the empty tank, if the water hasn't reached pt yet.
+    fn empty_at(&self, pt: &Point) -> Option<u16> {
+        if !self.intro_running() {
+            return None;
+        }
+
+        let filled = self.size.height * self.intro / INTRO_FRAMES;
+        if pt.y >= cvt(filled) {
+            self.config.intro
+        } else {
+            None
+        }
+    }
+
+    // Whether pt is on the outline of a fish's bounding box, with
+    // debug_boxes.  The outline is just inside the box, so it is erased
+    // along with the fish.
+    fn on_debug_box(&self, pt: &Point) -> bool {
+        if !self.config.debug_boxes {
+            return false;
+        }
+
+        self.fish_positions().any(|(upper_left, size, _)| {
+            let right = upper_left.x + cvt(size.width) - 1;
+            let bottom = upper_left.y + cvt(size.height) - 1;
+            let inside = pt.x >= upper_left.x && pt.x <= right &&
+                pt.y >= upper_left.y && pt.y <= bottom;
+            inside && (pt.x == upper_left.x || pt.x == right ||
+                       pt.y == upper_left.y || pt.y == bottom)
+        })
+    }
+
+    /// The color to clear the screen to before calling render_all() for
+    /// the first frame.  This is `background_color()`, unless the tank
+    /// is still empty, waiting for its intro.
+    pub fn clear_color(&self) -> u16 {
+        match self.config.intro {
+            Some(empty) if self.intro == 0 => empty,
+            _ => self.background_color(),
+        }
+    }
+
+    /// Same as swim(), but returns false if nothing on the screen
+    /// changed, in which case there's no need to call render().
+    pub fn step(&mut self) -> bool {
+        self.erasing = self.vacated.take();
+        self.mouths_opened = 0;
+        if self.paused {
+            return self.erasing.is_some();
+        }
+
+        // Fish which are added swim in from off the screen, so don't
+        // need to be counted here.
+        let mut before = [None; MAX_FISH];
+        for (look, fish) in before.iter_mut().zip(&self.fish[..self.num_fish]) {
+            *look = Some(fish.appearance());
+        }
+
+        // Work out which way every fish steers before changing any of
+        // them, so they all react to the others as they were at the end
+        // of the last frame.
+        let mut steering = [None; MAX_FISH];
+        for (i, velocity) in steering[..self.num_fish].iter_mut().enumerate() {
+            *velocity = self.steering(i);
+            let nudge = self.avoidance(i);
+            if nudge != 0 {
+                // Keep the nudges from adding up, for fish which don't
+                // steer back to their heading.
+                let drift = &self.config.drift_range;
+                let v = velocity.unwrap_or(self.fish[i].velocity);
+                let y = (v.y + nudge).max(drift.start.min(0) - AVOID_NUDGE)
+                    .min(drift.end.max(1) - 1 + AVOID_NUDGE);
+                *velocity = Some(Point::new(v.x, y));
+            }
+        }
+
+        for (fish, velocity) in self.fish.iter_mut().zip(&steering) {
+            if let Some(velocity) = *velocity {
+                fish.steer(velocity, self.config.turning);
+            }
+        }
+
+        let frame = self.frame_counter;
+        let mut randomized = false;
+        for i in 0..self.num_fish {
+            let fish = &mut self.fish[i];
+            let was_open = fish.frame() == MOUTH_OPEN_FRAME;
+            if fish.swim(&self.size, &self.config, frame, &mut self.rng) {
+                #[cfg(feature = "defmt")]
+                defmt::debug!("fish {} left the screen", i);
+                randomized = true;
+            } else if !was_open && fish.frame() == MOUTH_OPEN_FRAME &&
+                fish.fish_type.opens_mouth() {
+                self.mouths_opened |= 1 << i;
+            }
+        }
+
+        if randomized {
+            self.sort_by_depth();
+        }
+
+        self.update_bands();
+
+        let mut bg_before = [None; MAX_BG_FISH];
+        let bg_fish = &mut self.bg_fish[..self.num_bg_fish];
+        for (look, fish) in bg_before.iter_mut().zip(bg_fish) {
+            *look = Some(fish.appearance());
+            if fish.swim(&self.size, &self.config, frame, &mut self.rng) {
+                fish.make_distant(&self.size, &self.config, &mut self.rng);
+            }
+        }
+
+        for i in 0..self.num_bubbles {
+            self.bubbles[i].swim(&self.size, &mut self.rng);
+        }
+
+        // Food which gets eaten, or sinks to the bottom, goes away, and
+        // has to be erased.
+        let mut i = 0;
+        while i < self.num_food {
+            let pellet = &mut self.food[i];
+            pellet.sink(self.frame_counter);
+            let center = pellet.center();
+            let eaten = self.fish[..self.num_fish].iter()
+                .any(|fish| fish.covers(&center));
+            if eaten || pellet.upper_left.y >= cvt(self.size.height) {
+                let rect = pellet.dirty_rect(&self.size);
+                self.erasing = merge_rects(self.erasing, rect);
+                self.num_food -= 1;
+                self.food[i] = self.food[self.num_food];
+            } else {
+                i += 1;
+            }
+        }
+
+        self.frame_counter = self.frame_counter.wrapping_add(1);
+
+        let fish_changed = self.fish[..self.num_fish].iter().zip(&before)
+            .chain(self.bg_fish[..self.num_bg_fish].iter().zip(&bg_before))
+            .any(|(fish, &look)| look != Some(fish.appearance()));
+        let bubbles_changed = self.bubbles[..self.num_bubbles].iter()
+            .any(|b| b.center != b.prev_center);
+        let food_changed = self.food[..self.num_food].iter()
+            .any(|p| p.upper_left != p.prev_upper_left);
+        let seaweed_changed = !self.config.seaweed.is_empty() &&
+            self.frame_counter % SWAY_SPEED == 0;
+        let water_changed = self.water_changed();
+
+        fish_changed || bubbles_changed || food_changed || seaweed_changed ||
+            water_changed || self.erasing.is_some()
+    }
+
+    // The velocity fish i wants to change to, if any.
+    fn steering(&self, i: usize) -> Option<Point> {
+        let fish = &self.fish[i];
+        if fish.leaving() {
+            return Some(fish.exit());
+        }
+
+        match fish.behavior {
+            Behavior::Predator => self.nearest(i, Behavior::Prey)
+                .map(|(target, _)| fish.chase(target)),
+            Behavior::Prey => {
+                let radius = self.config.flee_radius;
+                match self.nearest(i, Behavior::Predator) {
+                    Some((threat, dist2)) if dist2 <= radius * radius =>
+                        Some(fish.flee(threat)),
+                    // Go back to the way it was going before it fled.
+                    _ => self.nearest_food(i)
+                        .map(|food| fish.chase(food))
+                        .or_else(|| self.flocking(i))
+                        .or(Some(fish.heading)),
+                }
+            }
+            Behavior::Drift => {
+                // Without a school to follow, go back to the way it was
+                // going before it went after food.
+                let heading = if self.config.flocking {
+                    None
+                } else {
+                    Some(fish.heading)
+                };
+                self.nearest_food(i)
+                    .map(|food| fish.chase(food))
+                    .or_else(|| self.flocking(i))
+                    .or(heading)
+            }
+        }
+    }
+
+    // How much fish i should speed up downwards to get out of the way of
+    // the fish it overlaps, or 0 if avoid_collisions is disabled.
+    fn avoidance(&self, i: usize) -> i32 {
+        let fish = &self.fish[i];
+        if !self.config.avoid_collisions ||
+            fish.behavior == Behavior::Predator ||
+            !fish.on_screen(&self.size) {
+            return 0;
+        }
+
+        let area = |f: &Fish<'_, FRAMES>| f.size.width * f.size.height;
+        let mut nudge = 0;
+        for (j, other) in self.fish[..self.num_fish].iter().enumerate() {
+            if j != i &&
+                fish.overlap(other) * 2 >= area(fish).min(area(other)) {
+                // Fish at the same height split up by their order.
+                let dy = fish.center().y - other.center().y;
+                nudge += match dy.signum() {
+                    0 if i < j => -1,
+                    0 => 1,
+                    sign => sign,
+                };
+            }
+        }
+        // Don't push fish off the top of the screen or into the sand.
+        let bottom = fish.upper_left.y + cvt(fish.size.height);
+        let water = cvt(self.config.terrain.water_height(&self.size));
+        let lo = if fish.upper_left.y <= 0 { 0 } else { -AVOID_NUDGE };
+        let hi = if bottom >= water { 0 } else { AVOID_NUDGE };
+        nudge.max(lo).min(hi)
+    }
+
+    // The center of the closest food pellet within food_radius of fish
+    // i, if the fish is on the screen.
+    fn nearest_food(&self, i: usize) -> Option<Point> {
+        let fish = &self.fish[i];
+        if !fish.on_screen(&self.size) {
+            return None;
+        }
+
+        let here = fish.center();
+        let radius2 = self.config.food_radius * self.config.food_radius;
+        self.food[..self.num_food].iter()
+            .map(|pellet| {
+                (pellet.center(), length2(pellet.center() - here))
+            })
+            .filter(|&(_, dist2)| dist2 <= radius2)
+            .min_by_key(|&(_, dist2)| dist2)
+            .map(|(center, _)| center)
+    }
+
+    // The velocity the rest of fish i's school pulls it towards, or
+    // None if flocking is disabled or there's no-one else in the school.
+    fn flocking(&self, i: usize) -> Option<Point> {
+        if !self.config.flocking {
+            return None;
+        }
+
+        let fish = &self.fish[i];
+        let here = fish.center();
+        let radius2 = self.config.flock_radius * self.config.flock_radius;
+        let mut count = 0;
+        let mut centers = Point::new(0, 0);
+        let mut velocities = Point::new(0, 0);
+        let mut crowding = Point::new(0, 0);
+        for (j, other) in self.fish[..self.num_fish].iter().enumerate() {
+            let d = here - other.center();
+            let dist2 = length2(d);
+            if j != i && other.species == fish.species &&
+                other.on_screen(&self.size) && dist2 <= radius2 {
+                count += 1;
+                centers += other.center();
+                velocities += other.velocity;
+                // Fish within half the radius are too close.
+                if dist2 * 4 <= radius2 {
+                    crowding += d;
+                }
+            }
+        }
+
+        if count == 0 {
+            None
+        } else {
+            Some(fish.flock(&self.config, count, centers, velocities,
+                            crowding))
+        }
+    }
+
+    // The center of the fish on the screen with the given behavior which
+    // is closest to fish i, and the square of its distance from fish i.
+    fn nearest(&self, i: usize, behavior: Behavior) -> Option<(Point, i32)> {
+        let here = self.fish[i].center();
+        self.fish[..self.num_fish].iter()
+            .filter(|f| f.behavior == behavior && f.on_screen(&self.size))
+            .map(|f| (f.center(), length2(f.center() - here)))
+            .min_by_key(|&(_, dist2)| dist2)
+    }
+
+    /// What happened during the most recent call to swim().
+    pub fn events(&self) -> impl Iterator<Item = Event> + '_ {
+        (0..self.num_fish)
+            .filter(move |&i| self.mouths_opened & 1 << i != 0)
+            .map(Event::MouthOpen)
+    }
+
+    /// Size of the tank in its own coordinates, which the fish, food,
+    /// and everything else are positioned in.  This is the display's
+    /// size divided by `upscale`, and rotated to the orientation.
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    /// The upper left corner, size, and direction of each fish, as of
+    /// the most recent call to swim(), for drawing things over the top
+    /// of them.  They are in the same order as the fish in events(),
+    /// and in the tank's coordinates, which are only the same as the
+    /// display's when the orientation is `Landscape` and there is no
+    /// `upscale`.  Background fish aren't included.
+    pub fn fish_positions(&self)
+                          -> impl Iterator<Item = (Point, Size, Dir)> + '_ {
+        self.fish[..self.num_fish].iter()
+            .map(|fish| (fish.upper_left, fish.size, fish.direction))
+    }
+
+    /// A hash of where every fish is, which way it is facing or turning,
+    /// and how far through its animation it is, for tests to check that
+    /// two tanks are in step without comparing what they draw.  The
+    /// random number generator isn't included.  The hash is 64-bit
+    /// FNV-1a over the fields in little-endian order, so it is the same
+    /// on every platform.
+    pub fn layout_hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut add = |bytes: &[u8]| {
+            for &b in bytes {
+                hash = (hash ^ u64::from(b)).wrapping_mul(0x100000001b3);
+            }
+        };
+        let fish = self.fish[..self.num_fish].iter()
+            .chain(&self.bg_fish[..self.num_bg_fish]);
+        for fish in fish {
+            add(&fish.upper_left.x.to_le_bytes());
+            add(&fish.upper_left.y.to_le_bytes());
+            add(&[fish.direction as u8, fish.animation, fish.turn]);
+        }
+        hash
+    }
+
+    /// Number of fish currently in the tank.
+    pub fn num_fish(&self) -> usize {
+        self.num_fish
+    }
+
+    /// Number of sprites the fish are drawn with, which is fewer than
+    /// NUM_SPRITES if the sprite data has fewer.
+    pub fn sprite_count(&self) -> usize {
+        self.sprite_count
+    }
+
+    /// The color of the water at the top of the tank, at the current
+    /// time of day.
+    pub fn background_color(&self) -> u16 {
+        self.background_at_frame(self.frame_counter)
+    }
+
+    /// The color of the water `y` pixels from the top of the tank, at
+    /// the current time of day, leaving out the `tile`.
+    pub fn background_at(&self, y: i32) -> u16 {
+        self.water_at(self.background_color(),
+                      self.depth_at_frame(self.frame_counter), y)
+    }
+
+    // The color of the water at pt, behind everything else.
+    fn water_behind(&self, pt: &Point) -> u16 {
+        self.water_point(self.background_color(),
+                         self.depth_at_frame(self.frame_counter), pt)
+    }
+
+    // The color of the water at pt, from the tile if there is one, or
+    // else shaded between the colors at the top and bottom of the tank.
+    fn water_point(&self, top: u16, bottom: u16, pt: &Point) -> u16 {
+        let width = size_index(self.config.tile_width);
+        let height = self.config.tile.len().checked_div(width).unwrap_or(0);
+        if height == 0 {
+            return self.water_at(top, bottom, pt.y);
+        }
+
+        let x = to_index(pt.x) % width;
+        let y = to_index(pt.y) % height;
+        lerp565(self.config.tile[x + y * width], self.config.night_color,
+                self.night_at_frame(self.frame_counter))
+    }
+
+    // Shade between the colors at the top and bottom of the tank.
+    fn water_at(&self, top: u16, bottom: u16, y: i32) -> u16 {
+        if top == bottom {
+            return top;
+        }
+
+        let last = (cvt(self.size.height) - 1).max(1);
+        let amount = y.max(0).min(last) * 255 / last;
+        lerp565(top, bottom, amount.try_into().unwrap())
+    }
+
+    fn background_at_frame(&self, frame: u32) -> u16 {
+        lerp565(self.config.day_color, self.config.night_color,
+                self.night_at_frame(frame))
+    }
+
+    fn depth_at_frame(&self, frame: u32) -> u16 {
+        lerp565(self.config.depth_color, self.config.night_color,
+                self.night_at_frame(frame))
+    }
+
+    // How far the water has faded towards night_color, out of 255.
+    fn night_at_frame(&self, frame: u32) -> u8 {
+        let day_length = self.config.day_length;
+        if day_length == 0 {
+            return 0;
+        }
+
+        // Fade to night during the first half of the day, and back
+        // during the second half.
+        let half = u64::from(day_length / 2).max(1);
+        let time = u64::from(frame % day_length);
+        let time = if time < half { time } else { u64::from(day_length) - time };
+        (time.min(half) * 255 / half).try_into().unwrap()
+    }
+
+    /// How much the caustics brighten the water at `pt`, in 5-bit color
+    /// units.  Always 0 unless `caustics` is enabled.
+    pub fn caustic_tint(&self, pt: &Point) -> i16 {
+        if !self.config.caustics {
+            return 0;
+        }
+
+        // A triangle wave along the diagonal, which moves one pixel per
+        // frame.
+        let period = cvt(CAUSTIC_PERIOD);
+        let frame = cvt(self.frame_counter % CAUSTIC_PERIOD);
+        let phase = (pt.x + pt.y + frame).rem_euclid(period);
+        let from_middle = (phase - period / 2).abs();
+        let tint = (CAUSTIC_WIDTH / 2 - from_middle).max(0) *
+            CAUSTIC_BRIGHTNESS * 2 / CAUSTIC_WIDTH;
+        tint.try_into().unwrap()
+    }
+
+    // Whether the color of the water changed during the most recent
+    // call to swim(), or the water is still filling the tank.
+    fn water_changed(&self) -> bool {
+        if self.config.caustics || self.intro_running() {
+            return true;
+        }
+
+        let prev_frame = self.frame_counter.wrapping_sub(1);
+        self.background_at_frame(prev_frame) != self.background_color() ||
+            self.depth_at_frame(prev_frame) !=
+            self.depth_at_frame(self.frame_counter)
+    }
+
+    fn screen_rect(&self) -> Option<Rectangle> {
+        let origin = Point::new(0, 0);
+        union_rect(origin, self.size, origin, self.size, &self.size)
+    }
+
+    /// The areas of the screen which need to be redrawn after the most
+    /// recent call to swim().  If the color of the water changed, that
+    /// is the whole screen.  The rectangles are in the tank's
+    /// coordinates, which only match the display's in the `Landscape`
+    /// orientation.
+    pub fn dirty_rects(&self) -> impl Iterator<Item = Rectangle> + '_ {
+        let water_changed = self.water_changed();
+        let screen = if water_changed { self.screen_rect() } else { None };
+
+        let fish = self.fish[..self.num_fish].iter()
+            .chain(&self.bg_fish[..self.num_bg_fish])
+            .filter_map(move |f| f.dirty_rect(&self.size));
+        let removed = self.vacated.into_iter().chain(self.erasing);
+        let bubbles = self.bubbles[..self.num_bubbles].iter()
+            .filter_map(move |b| b.dirty_rect(&self.size));
+        let food = self.food[..self.num_food].iter()
+            .filter_map(move |p| p.dirty_rect(&self.size));
+        // The seaweed only moves when the sway moves on to the next offset.
+        let seaweed: &[Seaweed] = if self.frame_counter % SWAY_SPEED == 0 {
+            self.config.seaweed
+        } else {
+            &[]
+        };
+        let seaweed = seaweed.iter()
+            .filter_map(move |s| s.bounds(&self.size));
+        let parts = fish.chain(removed).chain(bubbles).chain(food)
+            .chain(seaweed)
+            .filter(move |_| !water_changed);
+        screen.into_iter().chain(parts)
+    }
+
+    /// Draw the fish, bubbles, and seaweed onto the whole screen,
+    /// without erasing anything.
+    /// Suitable for the first frame after the target has been cleared
+    /// to `background_color()`.
+    pub fn render_all<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where D: DrawTarget<Rgb565>,
+    {
+        target.draw_iter(TankIterator::new(self))
+    }
+
+    /// Draw every pixel of the display into `buf`, in RGB565 format,
+    /// row by row, with each row starting `stride` pixels after the one
+    /// before, for sending somewhere other than a `DrawTarget`.
+    pub fn render_to_slice(&self,
+                           buf: &mut [u16],
+                           stride: usize) -> Result<(), TankError> {
+        let width = size_index(self.display_size.width);
+        let height = size_index(self.display_size.height);
+        if stride < width || buf.len() < stride.saturating_mul(height) {
+            return Err(TankError::BufferTooSmall);
+        }
+
+        for (y, row) in (0..).zip(buf.chunks_mut(stride).take(height)) {
+            for (dest, Pixel(_, color)) in row.iter_mut()
+                .zip(self.display_row(y)) {
+                *dest = RawU16::from(color).into_inner();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Draw row `y` of the display into the start of `line_buf`, for
+    /// sending the screen somewhere a row at a time without a buffer as
+    /// big as the whole of it.  Nothing is drawn for rows below the
+    /// bottom of the display.
+    pub fn render_row(&self,
+                      y: u32,
+                      line_buf: &mut [Rgb565]) -> Result<(), TankError> {
+        if line_buf.len() < size_index(self.display_size.width) {
+            return Err(TankError::BufferTooSmall);
+        }
+
+        if y < self.display_size.height {
+            for (dest, Pixel(_, color)) in line_buf.iter_mut()
+                .zip(self.display_row(cvt(y))) {
+                *dest = color;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Redraw only the areas which changed during the most recent call
+    /// to swim().
+    pub fn render<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where D: DrawTarget<Rgb565>,
+    {
+        for rect in self.dirty_rects() {
+            target.draw_iter(TankIterator::for_rect(self, rect))?;
+        }
+
+        Ok(())
+    }
+
+    /// Move on to the next frame with step(), call `between` with the
+    /// tank, and then draw whatever changed with render().  `between`
+    /// sees the tank exactly as it is about to be drawn, so it can keep
+    /// something else, like an LED, in step with the picture.  Returns
+    /// the result of step(), which is false if nothing needed drawing.
+    pub fn step_and_render<D, F>(&mut self,
+                                 target: &mut D,
+                                 between: F) -> Result<bool, D::Error>
+    where D: DrawTarget<Rgb565>,
+          F: FnOnce(&Self),
+    {
+        let changed = self.step();
+        between(self);
+        if changed {
+            self.render(target)?;
+        }
+
+        Ok(changed)
+    }
+
+    /// Like render(), but draws each row of the changed areas into
+    /// `line_buf`, and then hands it to `target` all at once, which is
+    /// much faster on displays where every write has to set up a window
+    /// first.  Rows wider than `line_buf` are drawn in pieces, so a
+    /// buffer as wide as the screen is best, but it must not be empty.
+    pub fn render_buffered<D>(&self,
+                              target: &mut D,
+                              line_buf: &mut [Rgb565]) -> Result<(), D::Error>
+    where D: LineTarget,
+    {
+        assert!(!line_buf.is_empty());
+        let buf_len = i32::try_from(line_buf.len()).unwrap_or(i32::MAX);
+        for rect in self.dirty_rects() {
+            let rect = self.rect_to_display(rect);
+            for y in rect.top_left.y..=rect.bottom_right.y {
+                let mut x = rect.top_left.x;
+                while x <= rect.bottom_right.x {
+                    let end = rect.bottom_right.x.min(x + (buf_len - 1));
+                    let piece = Rectangle::new(Point::new(x, y),
+                                               Point::new(end, y));
+                    let pixels = TankIterator::for_display_rect(self, piece);
+                    let mut len = 0;
+                    for (dest, Pixel(_, color)) in line_buf.iter_mut()
+                        .zip(pixels) {
+                        *dest = color;
+                        len += 1;
+                    }
+                    target.draw_row(piece.top_left, &line_buf[..len])?;
+                    x = end + 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Redraw every row of the display whose pixels changed since the
+    /// last call, without relying on `dirty_rects()`.  `row_hashes`
+    /// holds a hash of each row as it was last drawn, one per row of the
+    /// display; start it off as all zeros to draw every row.  Changed
+    /// rows wider than `line_buf` are worked out again and drawn in
+    /// pieces, so a buffer as wide as the screen is best.  This works
+    /// out every pixel on the display each time, so it is slower than
+    /// render_buffered(), but leaves no trails however fast the fish
+    /// swim.  A changed row can very occasionally hash the same as
+    /// before, in which case it isn't redrawn until it changes again.
+    ///
+    /// # Panics
+    ///
+    /// If `line_buf` is empty, or `row_hashes` has fewer entries than
+    /// the display has rows.
+    pub fn render_hashed<D>(&self,
+                            target: &mut D,
+                            row_hashes: &mut [u32],
+                            line_buf: &mut [Rgb565]) -> Result<(), D::Error>
+    where D: LineTarget,
+    {
+        let width = size_index(self.display_size.width);
+        let height = size_index(self.display_size.height);
+        assert!(!line_buf.is_empty());
+        assert!(row_hashes.len() >= height);
+        for (y, old_hash) in (0..).zip(&mut row_hashes[..height]) {
+            // 32-bit FNV-1a, a pixel at a time, keeping as much of the
+            // row in line_buf as fits.
+            let mut hash: u32 = 0x811c9dc5;
+            let mut dest = line_buf.iter_mut();
+            for Pixel(_, color) in self.display_row(y) {
+                if let Some(dest) = dest.next() {
+                    *dest = color;
+                }
+                let raw = RawU16::from(color).into_inner();
+                hash = (hash ^ u32::from(raw)).wrapping_mul(0x01000193);
+            }
+            if hash == *old_hash {
+                continue;
+            }
+            *old_hash = hash;
+            if width <= line_buf.len() {
+                target.draw_row(Point::new(0, y), &line_buf[..width])?;
+                continue;
+            }
+
+            let mut pixels = self.display_row(y).peekable();
+            while let Some(&Pixel(start, _)) = pixels.peek() {
+                let mut len = 0;
+                for (dest, Pixel(_, color)) in line_buf.iter_mut()
+                    .zip(&mut pixels) {
+                    *dest = color;
+                    len += 1;
+                }
+                target.draw_row(start, &line_buf[..len])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Redraw exactly the pixels whose color changed since the last
+    /// call, without relying on `dirty_rects()`.  `shadow` holds the
+    /// color of every pixel on the display, row by row, and is updated
+    /// as they are drawn, so it must start off matching the display,
+    /// for example by filling both with `background_color()`.  Each run
+    /// of changed pixels in a row is drawn in one go, in pieces of up to
+    /// the length of `line_buf`.  Like render_hashed(), this works out
+    /// every pixel on the display each time.
+    ///
+    /// # Panics
+    ///
+    /// If `line_buf` is empty, or `shadow` is smaller than the display.
+    #[cfg(feature = "shadow-framebuffer")]
+    pub fn render_shadowed<D>(&self,
+                              target: &mut D,
+                              shadow: &mut [u16],
+                              line_buf: &mut [Rgb565]) -> Result<(), D::Error>
+    where D: LineTarget,
+    {
+        let width = size_index(self.display_size.width);
+        let height = size_index(self.display_size.height);
+        assert!(!line_buf.is_empty());
+        assert!(shadow.len() >= width * height);
+        for (y, row) in (0..).zip(shadow.chunks_mut(width).take(height)) {
+            let mut start = 0;
+            let mut len = 0;
+            for (x, (old, Pixel(_, color))) in (0..).zip(row.iter_mut()
+                .zip(self.display_row(y))) {
+                let raw = RawU16::from(color).into_inner();
+                if raw != *old {
+                    *old = raw;
+                    if len == 0 {
+                        start = x;
+                    }
+                    line_buf[len] = color;
+                    len += 1;
+                    if len < line_buf.len() {
+                        continue;
+                    }
+                }
+                if len > 0 {
+                    target.draw_row(Point::new(start, y), &line_buf[..len])?;
+                    len = 0;
+                }
+            }
+            if len > 0 {
+                target.draw_row(Point::new(start, y), &line_buf[..len])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Every pixel in row y of the display, background included.
+    fn display_row(&self, y: i32) -> TankIterator<'_, R, FRAMES> {
+        let right = cvt(self.display_size.width) - 1;
+        let row = Rectangle::new(Point::new(0, y), Point::new(right, y));
+        TankIterator::for_display_rect(self, row)
+    }
+
+    // The point in the tank which is drawn at pt on the display.
+    fn to_tank(&self, pt: Point) -> Point {
+        let upscale = cvt(self.config.upscale.max(1));
+        // Any pixels left over at the right and bottom of the display
+        // repeat the last column and row of the tank.
+        let last = Point::new(cvt(self.scaled_size.width) - 1,
+                              cvt(self.scaled_size.height) - 1);
+        let scaled = Point::new((pt.x / upscale).min(last.x),
+                                (pt.y / upscale).min(last.y));
+        self.config.orientation.to_tank(scaled, self.scaled_size)
+    }
+
+    // The area of the display covered by rect in the tank.
+    fn rect_to_display(&self, rect: Rectangle) -> Rectangle {
+        let upscale = cvt(self.config.upscale.max(1));
+        let rect = self.config.orientation
+            .rect_to_display(rect, self.scaled_size);
+        let last = |p: i32, scaled: u32, display: u32| {
+            if p >= cvt(scaled) - 1 {
+                cvt(display) - 1
+            } else {
+                p * upscale + upscale - 1
+            }
+        };
+        let bottom_right = rect.bottom_right;
+        Rectangle::new(rect.top_left * upscale,
+                       Point::new(last(bottom_right.x,
+                                       self.scaled_size.width,
+                                       self.display_size.width),
+                                  last(bottom_right.y,
+                                       self.scaled_size.height,
+                                       self.display_size.height)))
+    }
+
+    pub fn get_point(&self, pt: &Point) -> PointValue {
+        if let Some((sprite, at)) = self.overlay {
+            match sprite.get_point(&(*pt - at), 0, FULL_SCALE) {
+                PointValue::Opaque(c) => return PointValue::Opaque(c),
+                PointValue::Translucent(c, alpha) => {
+                    let behind = match self.under_overlay(pt) {
+                        PointValue::Opaque(b) => b,
+                        _ => self.water_behind(pt),
+                    };
+                    return PointValue::Opaque(blend565(c, behind, alpha));
+                }
+                PointValue::Transparent | PointValue::OutOfRange => (),
+            }
+        }
+
+        self.under_overlay(pt)
+    }
+
+    // Everything at the point but the overlay.
+    fn under_overlay(&self, pt: &Point) -> PointValue {
+        if !self.config.bubbles_in_front {
+            return self.scene_point(pt, true);
+        }
+
+        // A bubble in front lets whatever is behind it show through.
+        let behind = self.scene_point(pt, false);
+        if !self.bubble_at(pt) {
+            behind
+        } else if let PointValue::Opaque(c) = behind {
+            PointValue::Opaque(blend565(BUBBLE_COLOR, c, BUBBLE_ALPHA))
+        } else {
+            PointValue::Opaque(BUBBLE_COLOR)
+        }
+    }
+
+    // Returns true if the point is on any bubble.
+    fn bubble_at(&self, pt: &Point) -> bool {
+        self.bubbles[..self.num_bubbles].iter().any(|b| b.get_point(pt))
+    }
+
+    // Everything at the point but the bubbles, which are only included
+    // if with_bubbles is true, behind the fish.
+    fn scene_point(&self, pt: &Point, with_bubbles: bool) -> PointValue {
+        let mask = if pt.y >= 0 && pt.y < cvt(self.size.height) {
+            self.bands[self.band(pt.y)]
+        } else {
+            // Off the screen, so there's no band to consult.
+            u32::MAX
+        };
+        self.point_behind(pt, mask, with_bubbles)
+    }
+
+    // Like scene_point(), but only including the fish whose bits are set
+    // in mask, as in bands.
+    fn point_behind(&self,
+                    pt: &Point,
+                    mut mask: u32,
+                    with_bubbles: bool) -> PointValue {
+        let mut ret = PointValue::OutOfRange;
+
+        // Visit the fish in this band from front to back.
+        while mask != 0 {
+            let n = mask.trailing_zeros() as usize;
+            mask &= mask - 1;
+            if n >= self.num_fish {
+                break;
+            }
+
+            let fish = &self.fish[self.order[n]];
+            #[cfg(test)]
+            self.visits.set(self.visits.get() + 1);
+            match fish.get_point(pt) {
+                PointValue::Opaque(c)   =>
+                    return PointValue::Opaque(self.hazed(fish, c, pt)),
+                PointValue::Translucent(c, alpha) => {
+                    let c = self.hazed(fish, c, pt);
+                    let behind = match self.point_behind(pt, mask,
+                                                         with_bubbles) {
+                        PointValue::Opaque(b) => b,
+                        _ => self.water_behind(pt),
+                    };
+                    return PointValue::Opaque(blend565(c, behind, alpha));
+                }
+                PointValue::Transparent => ret = PointValue::Transparent,
+                PointValue::OutOfRange  => (),
+            }
+        }
+
+        // Food is behind the fish, bubbles are behind the food,
+        // seaweed is behind the bubbles, the terrain is behind the
+        // seaweed, and the fish in the distance are behind it all.
+        for pellet in &self.food[..self.num_food] {
+            if pellet.get_point(pt) {
+                return PointValue::Opaque(FOOD_COLOR);
+            }
+        }
+
+        if with_bubbles && self.bubble_at(pt) {
+            return PointValue::Opaque(BUBBLE_COLOR);
+        }
+
+        for seaweed in self.config.seaweed {
+            if seaweed.get_point(pt, self.frame_counter) {
+                return PointValue::Opaque(SEAWEED_COLOR);
+            }
+        }
+
+        if let Some(c) = self.config.terrain.get_point(pt, &self.size) {
+            return PointValue::Opaque(c);
+        }
+
+        // The fish in the distance fade into the water behind them.
+        for fish in &self.bg_fish[..self.num_bg_fish] {
+            if let PointValue::Opaque(c) = fish.get_point(pt) {
+                let water = self.water_behind(pt);
+                return PointValue::Opaque(haze(c, water, BG_DIM));
+            }
+        }
+
+        ret
+    }
+
+    // A color from the fish, faded into the water according to how far
+    // back the fish is.
+    fn hazed(&self, fish: &Fish<'_, FRAMES>, c: u16, pt: &Point) -> u16 {
+        match Fish::<FRAMES>::depth_haze(fish.depth, &self.config) {
+            0      => c,
+            amount => haze(c, self.water_behind(pt), amount),
+        }
+    }
+}
+
+// The overlay borrows from the same data as the sprites, so it needs the
+// tank's lifetime.
+impl<'a, R: Rng, const FRAMES: usize> FishTank<'a, R, FRAMES> {
+    /// Draw frame 0 of `sprite` in front of everything else, such as a
+    /// logo, with its upper left corner at `position` in the tank's
+    /// coordinates.  Its transparent pixels let the tank show through,
+    /// just like a fish's.  There is only one overlay, so this replaces
+    /// any earlier one.  It isn't included in `save_state()`.
+    pub fn set_overlay(&mut self,
+                       sprite: Sprite<'a, FRAMES>,
+                       position: Point) {
+        self.clear_overlay();
+        self.overlay = Some((sprite, position));
+        self.vacated = merge_rects(self.vacated, self.overlay_rect());
+    }
+}
+
+impl<R: Rng, const FRAMES: usize> TankIterator<'_, R, FRAMES> {
+    /// Iterate over the whole screen, only producing pixels which are
+    /// covered by something.  Suitable for the first frame after a clear.
+    pub fn new<'a>(fish_tank: &'a FishTank<'a, R, FRAMES>)
+                   -> TankIterator<'a, R, FRAMES> {
+        let display = fish_tank.display_size;
+        let bottom_right = Point::new(cvt(display.width) - 1,
+                                      cvt(display.height) - 1);
+        TankIterator {
+            tank:     fish_tank,
+            background: fish_tank.background_color(),
+            depth:    fish_tank.depth_at_frame(fish_tank.frame_counter),
+            bounds:   Rectangle::new(Point::new(0, 0), bottom_right),
+            erase:    false,
+            position: Point::new(0, 0),
+            cached:   None,
+        }
+    }
+
+    /// Iterate over every pixel in a rectangle, painting the background
+    /// wherever there is no fish.  `rect` is in the tank's coordinates,
+    /// like the rectangles from `FishTank::dirty_rects`.
+    pub fn for_rect<'a>(fish_tank: &'a FishTank<'a, R, FRAMES>,
+                        rect: Rectangle) -> TankIterator<'a, R, FRAMES> {
+        let rect = fish_tank.rect_to_display(rect);
+        TankIterator::for_display_rect(fish_tank, rect)
+    }
+
+    // Like for_rect(), but rect is in the display's coordinates.
+    fn for_display_rect<'a>(fish_tank: &'a FishTank<'a, R, FRAMES>,
+                            rect: Rectangle)
+                            -> TankIterator<'a, R, FRAMES> {
+        TankIterator {
+            tank:     fish_tank,
+            background: fish_tank.background_color(),
+            depth:    fish_tank.depth_at_frame(fish_tank.frame_counter),
+            bounds:   rect,
+            erase:    true,
+            position: rect.top_left,
+            cached:   None,
+        }
+    }
+
+    fn pixel(&self, c: u16) -> Pixel<Rgb565> {
+        let c = lerp565(0, c, self.tank.brightness);
+        Pixel(self.position, rgb565(c))
+    }
+
+    // The color of the point in the tank, before it is dimmed, or None
+    // if it can be skipped.
+    fn color_at(&self, pt: &Point) -> Option<u16> {
+        let tank = self.tank;
+        let pv = match tank.empty_at(pt) {
+            Some(empty) => PointValue::Opaque(empty),
+            None if tank.on_debug_box(pt) =>
+                PointValue::Opaque(DEBUG_BOX_COLOR),
+            None        => tank.get_point(pt),
+        };
+        let water = tank.water_point(self.background, self.depth, pt);
+        let water = brighten565(water, tank.caustic_tint(pt));
+        // The screen was cleared to the color at the top, so deeper,
+        // lit-up, or dimmed water still has to be drawn even when not
+        // erasing.
+        let dimmed = tank.brightness != u8::MAX;
+        // While the tank fills, the screen may still be the empty color
+        // anywhere, so all of it has to be drawn.
+        let erase = self.erase || tank.intro_running();
+        match pv {
+            PointValue::OutOfRange
+                if erase || water != self.background || dimmed =>
+                Some(water),
+            PointValue::OutOfRange    => None,
+            PointValue::Transparent   => Some(water),
+            PointValue::Opaque(color) => Some(color),
+            PointValue::Translucent(color, alpha) =>
+                Some(blend565(color, water, alpha)),
+        }
+    }
+}
+
+impl<R: Rng, const FRAMES: usize> Iterator for TankIterator<'_, R, FRAMES> {
+    type Item = Pixel<Rgb565>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.position.y > self.bounds.bottom_right.y {
+                return None;
+            } else {
+                let pt = self.tank.to_tank(self.position);
+                let color = match self.cached {
+                    Some((cached, color)) if cached == pt => color,
+                    _ => {
+                        let color = self.color_at(&pt);
+                        self.cached = Some((pt, color));
+                        color
+                    }
+                };
+                let ret = color.map(|c| self.pixel(c));
+                self.position.x += 1;
+                if self.position.x > self.bounds.bottom_right.x {
+                    self.position.x = self.bounds.top_left.x;
+                    self.position.y += 1;
+                }
+
+                if ret.is_some() {
+                    return ret;
+                }
+            }
+        }
+    }
+}
+
+impl<'a, R: Rng, const FRAMES: usize> RunIterator<'a, R, FRAMES> {
+    pub fn new(pixels: TankIterator<'a, R, FRAMES>)
+               -> RunIterator<'a, R, FRAMES> {
+        RunIterator { pixels, next: None }
+    }
+
+    fn next_pixel(&mut self) -> Option<(Point, u16)> {
+        self.pixels.next()
+            .map(|Pixel(pt, color)| (pt, RawU16::from(color).into_inner()))
+    }
+}
+
+impl<R: Rng, const FRAMES: usize> Iterator for RunIterator<'_, R, FRAMES> {
+    type Item = (Point, u16, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (start, color) = match self.next.take() {
+            Some(first) => first,
+            None => self.next_pixel()?,
+        };
+
+        // TankIterator skips pixels when it isn't erasing, so check that
+        // each pixel is right after the one before.
+        let mut len = 1;
+        while let Some((pt, c)) = self.next_pixel() {
+            if pt.y == start.y && pt.x == start.x + len && c == color {
+                len += 1;
+            } else {
+                self.next = Some((pt, c));
+                break;
+            }
+        }
+
+        Some((start, color, len.try_into().unwrap()))
+    }
+}
+
+/// Building sprite data on the host, for tools which make or shrink
+/// `fish.raw`.  None of this is needed to draw the tank.
+#[cfg(feature = "std")]
+pub mod sprite_build {
+    use super::*;
+
+    /// One sprite for build_sprites(): its size, the color of the pixels
+    /// which aren't drawn, and each frame of animation, as `width` *
+    /// `height` RGB565 pixels in row-major order.
+    pub struct SpriteImage<'a> {
+        pub width:       u16,
+        pub height:      u16,
+        pub transparent: u16,
+        pub frames:      &'a [&'a [u16]],
+    }
+
+    /// Put `sprites` together into sprite data in version 4, or version 5
+    /// if there aren't NUM_SPRITES of them, with every frame stored as it
+    /// is, which make_sprite() reads back exactly.  Each sprite must have
+    /// from 1 to NUM_FRAMES frames, of the right size.  Give the result to
+    /// compress_sprites() to make it smaller.
+    pub fn build_sprites(sprites: &[SpriteImage<'_>])
+                         -> Result<Vec<u16>, SpriteError> {
+        const WORDS: usize = 4 + 2 * NUM_FRAMES;
+        let count = sprites.len();
+        let start = if count == NUM_SPRITES { 1 } else { 2 };
+        let mut out = vec![0; start + WORDS * count];
+        if count == NUM_SPRITES {
+            out[0] = 4 << 8;
+        } else {
+            out[0] = 5 << 8;
+            out[1] = count.try_into().unwrap();
+        }
+
+        for (sprite_num, sprite) in sprites.iter().enumerate() {
+            if sprite.width == 0 || sprite.height == 0 {
+                return Err(SpriteError::ZeroDimension);
+            }
+            let num_frames = sprite.frames.len();
+            if num_frames == 0 || num_frames > NUM_FRAMES {
+                return Err(SpriteError::BadFrameCount);
+            }
+
+            let header = start + WORDS * sprite_num;
+            out[header]     = sprite.width;
+            out[header + 1] = sprite.height;
+            // Only write the frame count if there are frames missing, as
+            // make_sprite() takes zero to mean all of them.
+            out[header + 2] = if num_frames < NUM_FRAMES {
+                u16::try_from(num_frames).unwrap() << 8
+            } else {
+                0
+            };
+            out[header + 3] = sprite.transparent;
+
+            let num_words =
+                usize::from(sprite.width) * usize::from(sprite.height);
+            for (frame_no, frame) in sprite.frames.iter().enumerate() {
+                if frame.len() != num_words {
+                    return Err(SpriteError::FrameOutOfRange);
+                }
+                let offset = u32::try_from(out.len()).unwrap();
+                out.extend_from_slice(frame);
+                out[header + 4 + 2 * frame_no] = offset as u16;
+                out[header + 5 + 2 * frame_no] = (offset >> 16) as u16;
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Run-length encode one frame of `width` * `height` pixels, in the
+    /// version 3 format described at the top of this file.  Returns None if
+    /// the encoded frame is too long for its row offsets to fit in a u16.
+    pub fn encode_rle(width: u32,
+                      height: u32,
+                      pixels: &[u16]) -> Option<Vec<u16>> {
+        let width: usize = width.try_into().unwrap();
+        let height: usize = height.try_into().unwrap();
+        let mut frame = vec![0; height];
+        for (y, row) in pixels.chunks(width).take(height).enumerate() {
+            frame[y] = u16::try_from(frame.len()).ok()?;
+            let mut x = 0;
+            while x < width {
+                let c = row[x];
+                let count = row[x..].iter()
+                    .take(usize::from(u16::MAX))
+                    .take_while(|&&p| p == c)
+                    .count();
+                frame.push(count.try_into().unwrap());
+                frame.push(c);
+                x += count;
+            }
+        }
+
+        Some(frame)
+    }
+
+    /// Encode one frame of pixels as 4-bit indices into `palette`, in the
+    /// version 3 format described at the top of this file.  Returns None if
+    /// a pixel isn't `transparent` and isn't in entries 1 to 15 of
+    /// `palette`.
+    pub fn encode_palette(palette: &[u16],
+                          transparent: u16,
+                          pixels: &[u16]) -> Option<Vec<u16>> {
+        let mut frame = vec![0; (pixels.len() + 3) / 4];
+        for (n, &c) in pixels.iter().enumerate() {
+            if c != transparent {
+                let index = palette.iter().take(PALETTE_SIZE).skip(1)
+                    .position(|&p| p == c)? + 1;
+                frame[n / 4] |= u16::try_from(index).unwrap() << (n % 4 * 4);
+            }
+        }
+
+        Some(frame)
+    }
+
+    /// Convert the sprites of `sprite_data` (up to NUM_SPRITES of them), in
+    /// any version, to version 4, or version 5 if there are fewer than
+    /// NUM_SPRITES, using whichever encoding makes each sprite smallest.
+    /// Write the result out as native-endian u16 words to get a smaller
+    /// replacement for `fish.raw`.
+    pub fn compress_sprites(sprite_data: &[u16])
+                            -> Result<Vec<u16>, SpriteError> {
+        const WORDS: usize = 4 + 2 * NUM_FRAMES;
+        let count = sprite_count(sprite_data).min(NUM_SPRITES);
+        // Version 5 has the sprite count before the headers.
+        let start = if count == NUM_SPRITES { 1 } else { 2 };
+        let mut out = vec![0; start + WORDS * count];
+        if count == NUM_SPRITES {
+            out[0] = 4 << 8;
+        } else {
+            out[0] = 5 << 8;
+            out[1] = count.try_into().unwrap();
+        }
+
+        for sprite_num in 0..count {
+            let sprite = DefaultSprite::make_sprite(sprite_num, sprite_data)?;
+            let width: usize = sprite.size.width.try_into().unwrap();
+            let height: usize = sprite.size.height.try_into().unwrap();
+
+            let mut raw: Vec<Vec<u16>> = Vec::new();
+            for frame_no in 0..usize::from(sprite.frame_count) {
+                let mut pixels = Vec::with_capacity(width * height);
+                for y in 0..height {
+                    for x in 0..width {
+                        pixels.push(sprite.pixel(frame_no, x, y));
+                    }
+                }
+                raw.push(pixels);
+            }
+
+            let len = |frames: &[Vec<u16>]| {
+                frames.iter().map(Vec::len).sum::<usize>()
+            };
+            let mut flags = 0;
+            let mut prefix = Vec::new();
+            let mut frames = raw.clone();
+
+            let rle: Option<Vec<Vec<u16>>> = raw.iter()
+                .map(|p| encode_rle(sprite.size.width, sprite.size.height, p))
+                .collect();
+            if let Some(rle) = rle {
+                if len(&rle) < len(&frames) {
+                    flags = RLE_FLAG;
+                    frames = rle;
+                }
+            }
+
+            let mut palette = vec![0; 1];
+            for &c in raw.iter().flatten() {
+                if c != sprite.transparent && !palette[1..].contains(&c) {
+                    palette.push(c);
+                }
+            }
+            palette.resize(PALETTE_SIZE.max(palette.len()), 0);
+            let indexed: Option<Vec<Vec<u16>>> = raw.iter()
+                .map(|p| encode_palette(&palette, sprite.transparent, p))
+                .collect();
+            if let Some(indexed) = indexed {
+                if PALETTE_SIZE + len(&indexed) < len(&frames) {
+                    flags = PALETTE_FLAG;
+                    prefix = palette;
+                    frames = indexed;
+                }
+            }
+
+            if sprite.alpha != u8::MAX {
+                // Re-encoding would lose which palette entries are
+                // translucent, so keep the sprite's palette as it is.
+                flags = PALETTE_FLAG | ALPHA_FLAG;
+                prefix = sprite.palette.to_vec();
+                frames = sprite.frames[..raw.len()].iter()
+                    .map(|frame| frame.to_vec())
+                    .collect();
+            }
+            out.extend_from_slice(&prefix);
+
+            let header = start + WORDS * sprite_num;
+            out[header]     = sprite.size.width.try_into().unwrap();
+            out[header + 1] = sprite.size.height.try_into().unwrap();
+            // Only write the frame count if there are frames missing, as
+            // make_sprite() takes zero to mean all of them.
+            if usize::from(sprite.frame_count) < NUM_FRAMES {
+                flags |= u16::from(sprite.frame_count) << 8;
+            }
+            out[header + 2] = flags;
+            out[header + 3] = sprite.transparent;
+            for (frame_no, frame) in frames.iter().enumerate() {
+                // Frames which are the same as an earlier one (as they are
+                // for fish which aren't animated) share its pixels.
+                let offset = match frames[..frame_no].iter()
+                    .position(|f| f == frame) {
+                    Some(earlier) => {
+                        let lo = u32::from(out[header + 4 + 2 * earlier]);
+                        let hi = u32::from(out[header + 5 + 2 * earlier]);
+                        lo | hi << 16
+                    }
+                    None => {
+                        let offset = u32::try_from(out.len()).unwrap();
+                        out.extend_from_slice(frame);
+                        offset
+                    }
+                };
+                out[header + 4 + 2 * frame_no] = offset as u16;
+                out[header + 5 + 2 * frame_no] = (offset >> 16) as u16;
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use byte_slice_cast::AsByteSlice;
+    use embedded_graphics::pixelcolor::PixelColor;
+    use rand::RngCore;
+    use rand::rngs::mock::StepRng;
+    use std::vec;
+    use std::vec::Vec;
+
+    // The sprite data is read as u16 words, so it has to be 2-byte
+    // aligned, which include_bytes! doesn't promise on its own.
+    #[repr(align(2))]
+    struct Aligned<T: ?Sized>(T);
+
+    static SPRITE_DATA: &Aligned<[u8]> = &Aligned(*include_bytes!("fish.raw"));
+
+    // Same size and seed as the Longan Nano program with "fixed-seed".
+    const WIDTH: u32 = 160;
+    const HEIGHT: u32 = 80;
+    const SEED: u64 = 0x1badd00d8badf00d;
+
+    fn screen() -> Size {
+        Size::new(WIDTH, HEIGHT)
+    }
+
+    // An in-memory screen of RGB565 pixels.
+    struct FrameBuffer {
+        size:   Size,
+        pixels: Vec<u16>,
+    }
+
+    impl FrameBuffer {
+        fn new(size: Size) -> FrameBuffer {
+            FrameBuffer {
+                size,
+                pixels: vec![BACKGROUND; (size.width * size.height) as usize],
+            }
+        }
+
+        // 64-bit FNV-1a hash of every pixel.
+        fn hash(&self) -> u64 {
+            let mut hash: u64 = 0xcbf29ce484222325;
+            for &p in &self.pixels {
+                for &b in &p.to_le_bytes() {
+                    hash = (hash ^ u64::from(b)).wrapping_mul(0x100000001b3);
+                }
+            }
+            hash
+        }
+    }
+
+    impl DrawTarget<Rgb565> for FrameBuffer {
+        type Error = ();
+
+        fn draw_pixel(&mut self, pixel: Pixel<Rgb565>) -> Result<(), ()> {
+            let Pixel(pt, color) = pixel;
+            let idx = pt.x as u32 + pt.y as u32 * self.size.width;
+            self.pixels[idx as usize] = RawU16::from(color).into_inner();
+            Ok(())
+        }
+
+        fn size(&self) -> Size {
+            self.size
+        }
+    }
+
+    // A one-pixel display of any color, which remembers what was drawn.
+    struct OnePixel<C>(Option<C>);
+
+    impl<C: PixelColor> DrawTarget<C> for OnePixel<C> {
+        type Error = ();
+
+        fn draw_pixel(&mut self, pixel: Pixel<C>) -> Result<(), ()> {
+            self.0 = Some(pixel.1);
+            Ok(())
+        }
+
+        fn size(&self) -> Size {
+            Size::new(1, 1)
+        }
+    }
+
+    // Random numbers which never let a fish move or jitter.  Every u32
+    // is 0, which gen_range() takes as the bottom of the range, and
+    // every u64 is u64::MAX, which makes gen_ratio() false.  Ranges of
+    // u64 or usize would never be satisfied, so this is only good for
+    // tanks which don't ask for those.
+    struct NoMove;
+
+    impl RngCore for NoMove {
+        fn next_u32(&mut self) -> u32 {
+            0
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            u64::MAX
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.fill(0);
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8])
+                          -> Result<(), rand::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    // Random numbers which are all 0, so gen_range() gives the bottom
+    // of the range, gen() gives false, and gen_ratio() is always true.
+    fn zeros() -> StepRng {
+        StepRng::new(0, 0)
+    }
+
+    const RED:   u16 = 0xf800;
+    const GREEN: u16 = 0x07e0;
+    const BLUE:  u16 = 0x001f;
+    const WHITE: u16 = 0xffff;
+
+    fn rgb(r: u16, g: u16, b: u16) -> u16 {
+        r << 11 | g << 5 | b
+    }
+
+    // Sprite data in version 5, with FRAMES frame offsets per sprite.
+    // Each sprite is given as its width, height, and the words of each
+    // of its frames, which are stored one after another, and it has
+    // TRANSPARENT as its transparent color.  Sprites with fewer than
+    // FRAMES frames say so in their flags.
+    fn sheet<const FRAMES: usize>(sprites: &[(u16, u16, &[&[u16]])])
+                                  -> Vec<u16> {
+        let words = 4 + 2 * FRAMES;
+        let mut data = vec![0; 2 + words * sprites.len()];
+        data[0] = 5 << 8;
+        data[1] = sprites.len() as u16;
+        for (n, &(width, height, frames)) in sprites.iter().enumerate() {
+            let header = 2 + words * n;
+            data[header] = width;
+            data[header + 1] = height;
+            if frames.len() < FRAMES {
+                data[header + 2] = (frames.len() as u16) << 8;
+            }
+            data[header + 3] = TRANSPARENT;
+            for (frame_no, frame) in frames.iter().enumerate() {
+                let offset = data.len() as u32;
+                data[header + 4 + 2 * frame_no] = offset as u16;
+                data[header + 5 + 2 * frame_no] = (offset >> 16) as u16;
+                data.extend_from_slice(frame);
+            }
+        }
+        data
+    }
+
+    // Where each word of the header of the first sprite in a sheet() is.
+    const FLAGS: usize = 4;
+    const TRANSPARENT_COLOR: usize = 5;
+    const FRAME_OFFSETS: usize = 6;
+
+    // A sheet with one sprite, of a single color.
+    fn solid(width: u16, height: u16, color: u16) -> Vec<u16> {
+        let pixels = vec![color; usize::from(width) * usize::from(height)];
+        sheet::<NUM_FRAMES>(&[(width, height, &[&pixels])])
+    }
+
+    fn tank_of<'a>(data: &'a [u16],
+                   config: TankConfig<'a>) -> DefaultFishTank<'a> {
+        FishTank::with_config(screen(), data.as_byte_slice(), SEED, config)
+            .unwrap()
+    }
+
+    fn config(fish_count: usize) -> TankConfig<'static> {
+        TankConfig { fish_count, ..TankConfig::default() }
+    }
+
+    // Every pixel of the display, row by row.
+    fn snapshot<R: Rng, const FRAMES: usize>(tank: &FishTank<'_, R, FRAMES>)
+                                             -> Vec<u16> {
+        let size = tank.display_size;
+        let mut buf = vec![0; (size.width * size.height) as usize];
+        tank.render_to_slice(&mut buf, size.width as usize).unwrap();
+        buf
+    }
+
+    fn pixel_at(pixels: &[u16], x: u32, y: u32) -> u16 {
+        pixels[(x + y * WIDTH) as usize]
+    }
+
+    fn opaque(pv: PointValue) -> Option<u16> {
+        match pv {
+            PointValue::Opaque(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    // Put fish i at `at`, going at `velocity`, as if it had always been
+    // there.
+    fn place<R: Rng, const FRAMES: usize>(tank: &mut FishTank<'_, R, FRAMES>,
+                                          i: usize,
+                                          at: Point,
+                                          velocity: Point) {
+        place_fish(&mut tank.fish[i], at, velocity);
+        tank.sort_by_depth();
+        tank.update_bands();
+    }
+
+    fn place_fish<const FRAMES: usize>(fish: &mut Fish<'_, FRAMES>,
+                                       at: Point,
+                                       velocity: Point) {
+        fish.upper_left = at;
+        fish.prev_upper_left = at;
+        fish.prev_size = fish.size;
+        fish.velocity = velocity;
+        fish.heading = velocity;
+        fish.speed = velocity.x.abs().max(velocity.y.abs());
+        fish.direction = if velocity.x < 0 { Dir::Left } else { Dir::Right };
+        fish.turn = 0;
+        fish.hover_frames = 0;
+    }
+
+    // A fish on its own, drawn with the first sprite of `data`.
+    fn lone_fish(data: &[u16]) -> Fish<'_> {
+        Fish::new(DefaultSprite::make_sprite(0, data).unwrap())
+    }
+
+    // Everything about a fish which swim() can change.
+    #[allow(clippy::type_complexity)]
+    fn fish_state(fish: &Fish<'_>)
+                  -> ((Point, Point, Size, u8, u8, i32),
+                      (Point, i32, Point, u8, u8, Option<i32>),
+                      (u8, Point, Point, u32)) {
+        ((fish.upper_left, fish.prev_upper_left, fish.prev_size,
+          fish.direction as u8, fish.animation, fish.stride),
+         (fish.velocity, fish.speed, fish.heading, fish.turn, fish.depth,
+          fish.bob),
+         (fish.hover_frames, fish.glide, fish.subpixel, fish.age))
+    }
+
+    // Hash of the screen after render_all(), and after each of the
+    // following calls to swim() and render().  If a change to the fish
+    // is intentional, the failing test prints the new hashes.
+    const GOLDEN: [u64; 9] = [
+        0x43f8ac3ae4d73025,
+        0xdbf53dc0a74fe4e0,
+        0x01b153ad6f005517,
+        0x5cdee21326767890,
+        0x55a6fb7eb571b26a,
+        0x9d7af0e8b854c7d8,
+        0x9a72c58cd8d62f00,
+        0x932b463128e80661,
+        0x6f0c02eff77fa644,
+    ];
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn golden_frames() {
+        let mut fb = FrameBuffer::new(screen());
+        let mut tank = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+
+        let mut hashes = [0; GOLDEN.len()];
+        tank.render_all(&mut fb).unwrap();
+        hashes[0] = fb.hash();
+        for hash in &mut hashes[1..] {
+            tank.swim();
+            tank.render(&mut fb).unwrap();
+            *hash = fb.hash();
+        }
+
+        let new: std::string::String = hashes.iter()
+            .map(|hash| std::format!("    {:#018x},\n", hash))
+            .collect();
+        assert!(hashes == GOLDEN, "frames differ from GOLDEN:\n{}", new);
+    }
+
+    #[test]
+    fn oversized_sprites_are_placed_at_the_edge() {
+        let tall = vec![RED; 4 * 81];
+        let wide = vec![RED; 161 * 4];
+        let data = sheet::<NUM_FRAMES>(&[(4, 81, &[&tall]),
+                                         (161, 4, &[&wide])]);
+        let mut tank = tank_of(&data, config(2));
+        assert_eq!(tank.fish[0].upper_left.y, 0);
+        assert_eq!(tank.fish[1].upper_left.x, 0);
+        for _ in 0..10 {
+            tank.swim();
+        }
+    }
+
+    #[test]
+    fn fast_fish_is_randomized_after_leaving() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig { speed_range: 8..9, ..TankConfig::default() };
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        let mut fish = lone_fish(&data);
+        place_fish(&mut fish, Point::new(cvt(WIDTH) - 4, 40),
+                   Point::new(8, 0));
+
+        let mut randomized = false;
+        for _ in 0..10 {
+            if fish.swim(&screen(), &config, 0, &mut rng) {
+                randomized = true;
+                break;
+            }
+        }
+        assert!(randomized);
+        assert!(fish.on_screen(&screen()));
+        assert_eq!(fish.speed, 8);
+        assert_eq!(fish.velocity.x.abs(), 8);
+    }
+
+    #[test]
+    fn deeper_fish_is_in_front() {
+        let red = [RED; 16];
+        let green = [GREEN; 16];
+        let data = sheet::<NUM_FRAMES>(&[(4, 4, &[&red]), (4, 4, &[&green])]);
+        let config = TankConfig { depth_range: 0..10, ..config(2) };
+        let mut tank = tank_of(&data, config);
+        place(&mut tank, 0, Point::new(10, 10), Point::new(1, 0));
+        place(&mut tank, 1, Point::new(11, 11), Point::new(1, 0));
+        let pt = Point::new(12, 12);
+
+        tank.fish[0].depth = 7;
+        tank.fish[1].depth = 2;
+        tank.sort_by_depth();
+        tank.update_bands();
+        assert_eq!(opaque(tank.get_point(&pt)), Some(RED));
+
+        tank.fish[0].depth = 2;
+        tank.fish[1].depth = 7;
+        tank.sort_by_depth();
+        tank.update_bands();
+        assert_eq!(opaque(tank.get_point(&pt)), Some(GREEN));
+    }
+
+    #[test]
+    fn half_scale_samples_every_other_pixel() {
+        let pixels: Vec<u16> = (1..=16).collect();
+        let data = sheet::<NUM_FRAMES>(&[(4, 4, &[&pixels])]);
+        let sprite = DefaultSprite::make_sprite(0, &data).unwrap();
+        for y in 0..2 {
+            for x in 0..2 {
+                let pv = sprite.get_point(&Point::new(x, y), 0,
+                                          FULL_SCALE / 2);
+                let source = pixels[(2 * x + 2 * y * 4) as usize];
+                assert_eq!(opaque(pv), Some(source));
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_rng_gives_fixed_placement() {
+        // With nothing but zeros, every fish starts at the top left,
+        // facing right.
+        let tank = FishTank::with_rng(screen(), &SPRITE_DATA.0, zeros(),
+                                      TankConfig::default()).unwrap();
+        assert_eq!(tank.fish_positions().count(), NUM_FISH);
+        for ((upper_left, _, direction), fish) in tank.fish_positions()
+            .zip(&tank.fish) {
+            assert_eq!(upper_left, Point::new(0, 0));
+            assert!(direction == Dir::Right);
+            assert_eq!(fish.animation, 0);
+        }
+    }
+
+    #[test]
+    fn bands_skip_fish_which_cannot_cover_a_row() {
+        let mut tank = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        for _ in 0..5 {
+            tank.swim();
+        }
+        // The number of fish looked at while drawing the whole screen,
+        // against putting every fish in every band.
+        tank.visits.set(0);
+        snapshot(&tank);
+        let visited = tank.visits.get();
+        tank.bands = [u32::MAX; NUM_BANDS];
+        tank.visits.set(0);
+        snapshot(&tank);
+        let every = tank.visits.get();
+        assert!(visited < every / 2, "{} of {}", visited, every);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn run_length_frame_round_trips() {
+        let pixels = [TRANSPARENT, TRANSPARENT, RED, RED, RED,
+                      GREEN, TRANSPARENT, TRANSPARENT, BLUE, BLUE];
+        let encoded = sprite_build::encode_rle(5, 2, &pixels).unwrap();
+        let mut data = sheet::<NUM_FRAMES>(&[(5, 2, &[&encoded])]);
+        data[FLAGS] |= RLE_FLAG;
+        let sprite = DefaultSprite::make_sprite(0, &data).unwrap();
+        for (n, &c) in pixels.iter().enumerate() {
+            let pt = Point::new(n as i32 % 5, n as i32 / 5);
+            let expected = if c == TRANSPARENT { None } else { Some(c) };
+            assert_eq!(opaque(sprite.get_point(&pt, 0, FULL_SCALE)),
+                       expected);
+        }
+    }
+
+    #[test]
+    fn palette_sprite_decodes() {
+        let mut palette = [0; PALETTE_SIZE];
+        palette[1] = RED;
+        palette[2] = GREEN;
+        palette[3] = BLUE;
+        // Four pixels with indices 0 to 3, packed into one word, after
+        // the palette, which frame 0's offset has to skip.
+        let mut words = palette.to_vec();
+        words.push(0x3210);
+        let mut data = sheet::<NUM_FRAMES>(&[(4, 1, &[&words])]);
+        data[FLAGS] |= PALETTE_FLAG;
+        data[FRAME_OFFSETS] += PALETTE_SIZE as u16;
+
+        let sprite = DefaultSprite::make_sprite(0, &data).unwrap();
+        let at = |x| sprite.get_point(&Point::new(x, 0), 0, FULL_SCALE);
+        assert!(matches!(at(0), PointValue::Transparent));
+        assert_eq!(opaque(at(1)), Some(RED));
+        assert_eq!(opaque(at(2)), Some(GREEN));
+        assert_eq!(opaque(at(3)), Some(BLUE));
+    }
+
+    #[test]
+    fn frame_offsets_near_u32_max_are_out_of_range() {
+        for &flags in &[0, PALETTE_FLAG] {
+            let mut data = solid(4, 1, RED);
+            data[FLAGS] |= flags;
+            data[FRAME_OFFSETS] = 0xfffe;
+            data[FRAME_OFFSETS + 1] = 0xffff;
+            assert!(DefaultSprite::make_sprite(0, &data).err() ==
+                    Some(SpriteError::FrameOutOfRange));
+        }
+    }
+
+    #[test]
+    fn black_can_be_the_transparent_color() {
+        let pixels = [0x0000, WHITE];
+        let mut data = sheet::<NUM_FRAMES>(&[(2, 1, &[&pixels])]);
+        let sprite = DefaultSprite::make_sprite(0, &data).unwrap();
+        let black = sprite.get_point(&Point::new(0, 0), 0, FULL_SCALE);
+        assert_eq!(opaque(black), Some(0x0000));
+
+        data[TRANSPARENT_COLOR] = 0x0000;
+        let sprite = DefaultSprite::make_sprite(0, &data).unwrap();
+        let at = |x| sprite.get_point(&Point::new(x, 0), 0, FULL_SCALE);
+        assert!(matches!(at(0), PointValue::Transparent));
+        assert_eq!(opaque(at(1)), Some(WHITE));
+    }
+
+    #[test]
+    fn prey_flees_from_predator() {
+        let config = TankConfig { predator: true, ..config(2) };
+        let mut tank = FishTank::with_config(screen(), &SPRITE_DATA.0, SEED,
+                                             config).unwrap();
+        assert_eq!(tank.fish[0].behavior(), Behavior::Predator);
+        // A predator which doesn't move, with its prey right next to it.
+        place(&mut tank, 0, Point::new(40, 20), Point::new(0, 0));
+        place(&mut tank, 1, Point::new(60, 28), Point::new(2, 0));
+        let distance = |tank: &DefaultFishTank<'_>| {
+            length2(tank.fish[1].center() - tank.fish[0].center())
+        };
+        let before = distance(&tank);
+        for _ in 0..8 {
+            tank.swim();
+        }
+        assert!(distance(&tank) > before);
+    }
+
+    #[test]
+    fn school_lines_up() {
+        let config = TankConfig {
+            flocking: true,
+            species:  &[(0, 1)],
+            ..config(2)
+        };
+        let mut tank = FishTank::with_config(screen(), &SPRITE_DATA.0, SEED,
+                                             config).unwrap();
+        place(&mut tank, 0, Point::new(40, 30), Point::new(2, 2));
+        place(&mut tank, 1, Point::new(60, 30), Point::new(2, -2));
+        let apart = |tank: &DefaultFishTank<'_>| {
+            length2(tank.fish[0].velocity - tank.fish[1].velocity)
+        };
+        let before = apart(&tank);
+        for _ in 0..4 {
+            tank.swim();
+            assert!(apart(&tank) < before);
+        }
+    }
+
+    #[test]
+    fn golden_fish_turn_up_at_the_configured_rate() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig { rare_odds: 8, ..TankConfig::default() };
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        let mut fish = lone_fish(&data);
+        let mut golden = 0;
+        for _ in 0..8000 {
+            fish.randomize(&screen(), &config, &mut rng);
+            golden += u32::from(fish.golden);
+        }
+        // One in eight, give or take about three standard deviations.
+        assert!((900..1100).contains(&golden), "{} golden fish", golden);
+    }
+
+    #[test]
+    fn builder_defaults_match_new() {
+        let built = FishTankBuilder::new(&SPRITE_DATA.0).build(screen())
+            .unwrap();
+        let new = FishTank::new(screen(), &SPRITE_DATA.0, DEFAULT_SEED)
+            .unwrap();
+        assert!(snapshot(&built) == snapshot(&new));
+    }
+
+    #[test]
+    fn reset_matches_new() {
+        let mut reset = FishTank::new(screen(), &SPRITE_DATA.0, 1).unwrap();
+        for _ in 0..5 {
+            reset.swim();
+        }
+        reset.reset(SEED);
+        let mut new = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        for _ in 0..5 {
+            assert!(snapshot(&reset) == snapshot(&new));
+            reset.swim();
+            new.swim();
+        }
+    }
+
+    #[test]
+    fn step_is_false_when_nothing_moves() {
+        // Slow enough that no fish moves on to its next frame either.
+        let config = TankConfig {
+            animation_speed: 80..81,
+            ..TankConfig::default()
+        };
+        let mut tank = FishTank::with_rng(screen(), &SPRITE_DATA.0, NoMove,
+                                          config).unwrap();
+        for _ in 0..10 {
+            assert!(!tank.step());
+        }
+    }
+
+    #[test]
+    fn orientations_map_points() {
+        let display = screen();
+        let pt = Point::new(3, 1);
+        let cases = [
+            (Orientation::Landscape,        Point::new(3, 1)),
+            (Orientation::Portrait,         Point::new(158, 3)),
+            (Orientation::LandscapeFlipped, Point::new(156, 78)),
+            (Orientation::PortraitFlipped,  Point::new(1, 76)),
+        ];
+        for &(orientation, on_display) in &cases {
+            assert_eq!(orientation.to_display(pt, display), on_display);
+            assert_eq!(orientation.to_tank(on_display, display), pt);
+        }
+        assert_eq!(Orientation::Portrait.tank_size(display),
+                   Size::new(HEIGHT, WIDTH));
+    }
+
+    // Draw `tank` with render_all(), and then with render() after each
+    // of `steps` calls to swim(), checking each time that nothing is
+    // left behind compared with drawing every pixel.
+    fn assert_no_trail<R: Rng>(tank: &mut FishTank<'_, R>, steps: usize) {
+        let mut fb = FrameBuffer::new(screen());
+        tank.render_all(&mut fb).unwrap();
+        assert!(fb.pixels == snapshot(tank));
+        for _ in 0..steps {
+            tank.swim();
+            tank.render(&mut fb).unwrap();
+            assert!(fb.pixels == snapshot(tank));
+        }
+    }
+
+    #[test]
+    fn one_pixel_move_leaves_no_trail() {
+        let data = solid(4, 4, RED);
+        let mut tank = FishTank::with_rng(screen(), data.as_byte_slice(),
+                                          zeros(), config(1)).unwrap();
+        let start = tank.fish[0].upper_left;
+        assert_no_trail(&mut tank, 5);
+        assert_eq!(tank.fish[0].upper_left, start + Point::new(5, 0));
+    }
+
+    #[test]
+    fn four_frame_sprite() {
+        let frames: [&[u16]; 4] = [&[RED], &[GREEN], &[BLUE], &[WHITE]];
+        let data = sheet::<4>(&[(1, 1, &frames)]);
+        let sprite = Sprite::<4>::make_sprite(0, &data).unwrap();
+        assert_eq!(sprite.frame_count(), 4);
+        for (animation, frame) in (0..).zip(&frames) {
+            let pv = sprite.get_point(&Point::new(0, 0), animation,
+                                      FULL_SCALE);
+            assert_eq!(opaque(pv), Some(frame[0]));
+        }
+
+        let tank = FishTank::<Pcg32, 4>::with_frames(
+            screen(), data.as_byte_slice(), Pcg32::new(SEED, DEFAULT_STREAM),
+            TankConfig::default());
+        assert!(tank.is_ok());
+    }
+
+    #[test]
+    fn one_frame_sprite_stays_on_frame_0() {
+        let data = solid(2, 2, RED);
+        let sprite = DefaultSprite::make_sprite(0, &data).unwrap();
+        assert_eq!(sprite.frame_count(), 1);
+        for animation in 0..10 {
+            let pv = sprite.get_point(&Point::new(0, 0), animation,
+                                      FULL_SCALE);
+            assert_eq!(opaque(pv), Some(RED));
+        }
+
+        let mut tank = tank_of(&data, config(1));
+        for _ in 0..20 {
+            tank.swim();
+            assert_eq!(tank.fish[0].frame(), 0);
+        }
+    }
+
+    #[test]
+    fn fish_eats_nearby_food() {
+        let data = solid(8, 8, RED);
+        let mut tank = tank_of(&data, config(1));
+        place(&mut tank, 0, Point::new(40, 40), Point::new(1, 0));
+        assert!(tank.drop_food(Point::new(54, 44)));
+        for _ in 0..20 {
+            tank.swim();
+        }
+        assert_eq!(tank.num_food, 0);
+        assert!(tank.fish[0].upper_left.x > 40);
+    }
+
+    #[test]
+    fn paused_tank_stays_still() {
+        let mut paused = FishTank::new(screen(), &SPRITE_DATA.0, SEED)
+            .unwrap();
+        let mut running = FishTank::new(screen(), &SPRITE_DATA.0, SEED)
+            .unwrap();
+        let fish = |tank: &DefaultFishTank<'_>| {
+            tank.fish.iter().map(fish_state).collect::<Vec<_>>()
+        };
+
+        paused.set_paused(true);
+        for _ in 0..5 {
+            assert!(!paused.step());
+        }
+        assert!(fish(&paused) == fish(&running));
+        assert_eq!(paused.frame_counter, 0);
+
+        // Nothing was taken from the random number generator either.
+        paused.set_paused(false);
+        paused.swim();
+        running.swim();
+        assert!(fish(&paused) == fish(&running));
+    }
+
+    #[test]
+    fn gradient_midpoint() {
+        let tank = FishTankBuilder::new(&SPRITE_DATA.0)
+            .fish_count(0)
+            .gradient(0x0000, WHITE)
+            .build(screen())
+            .unwrap();
+        assert_eq!(tank.background_at(0), 0x0000);
+        assert_eq!(tank.background_at(cvt(HEIGHT) - 1), WHITE);
+        // Half way down, each channel is half way, rounded down.
+        assert_eq!(tank.background_at(40), rgb(15, 31, 15));
+    }
+
+    #[test]
+    fn caustics_vary() {
+        let config = TankConfig { caustics: true, ..config(0) };
+        let mut tank = FishTank::with_config(screen(), &SPRITE_DATA.0, SEED,
+                                             config).unwrap();
+        let row = |tank: &DefaultFishTank<'_>| {
+            (0..cvt(CAUSTIC_PERIOD))
+                .map(|x| tank.caustic_tint(&Point::new(x, 10)))
+                .collect::<Vec<_>>()
+        };
+        let before = row(&tank);
+        assert!(before.contains(&0));
+        assert!(before.iter().any(|&tint| tint > 0));
+        tank.swim();
+        assert!(row(&tank) != before);
+
+        let plain = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        assert_eq!(plain.caustic_tint(&Point::new(16, 0)), 0);
+    }
+
+    #[test]
+    fn sand_shows_where_there_are_no_fish() {
+        let config = TankConfig { terrain: Terrain::new(8, &[]), ..config(0) };
+        let tank = FishTank::with_config(screen(), &SPRITE_DATA.0, SEED,
+                                         config).unwrap();
+        let sand = opaque(tank.get_point(&Point::new(10, 75)));
+        assert_eq!(sand, Some(SAND_COLOR));
+        assert_eq!(opaque(tank.get_point(&Point::new(10, 70))), None);
+
+        let config = TankConfig {
+            terrain: Terrain::new(8, &[]),
+            ..TankConfig::default()
+        };
+        let tank = FishTank::with_config(screen(), &SPRITE_DATA.0, SEED,
+                                         config).unwrap();
+        for (upper_left, size, _) in tank.fish_positions() {
+            assert!(upper_left.y + cvt(size.height) <= cvt(HEIGHT) - 8);
+        }
+    }
+
+    #[test]
+    fn fish_hides_background_fish() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig { background_fish_count: 1, ..config(1) };
+        let mut tank = tank_of(&data, config);
+        let pt = Point::new(20, 20);
+        place_fish(&mut tank.bg_fish[0], pt, Point::new(1, 0));
+        place(&mut tank, 0, Point::new(19, 19), Point::new(1, 0));
+        assert_eq!(opaque(tank.get_point(&pt)), Some(RED));
+
+        place(&mut tank, 0, Point::new(100, 40), Point::new(1, 0));
+        let distant = haze(RED, tank.water_behind(&pt), BG_DIM);
+        assert_eq!(opaque(tank.get_point(&pt)), Some(distant));
+    }
+
+    #[test]
+    fn half_brightness_halves_each_channel() {
+        let mut tank = FishTankBuilder::new(&SPRITE_DATA.0)
+            .fish_count(0)
+            .background(WHITE)
+            .build(screen())
+            .unwrap();
+        tank.set_brightness(128);
+        assert!(snapshot(&tank).iter().all(|&c| c == rgb(15, 31, 15)));
+    }
+
+    #[test]
+    fn overlapping_fish_separate() {
+        let data = solid(8, 8, RED);
+        let config = TankConfig { avoid_collisions: true, ..config(2) };
+        let mut tank = tank_of(&data, config);
+        place(&mut tank, 0, Point::new(40, 30), Point::new(1, 0));
+        place(&mut tank, 1, Point::new(40, 32), Point::new(1, 0));
+        let before = tank.fish[0].overlap(&tank.fish[1]);
+        for _ in 0..10 {
+            tank.swim();
+        }
+        assert!(tank.fish[0].overlap(&tank.fish[1]) < before);
+    }
+
+    #[test]
+    fn bobbing_stays_within_amplitude() {
+        let data = solid(8, 8, RED);
+        let config = TankConfig { bobbing: true, ..TankConfig::default() };
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        let mut fish = lone_fish(&data);
+        fish.randomize(&screen(), &config, &mut rng);
+        place_fish(&mut fish, Point::new(20, 30), Point::new(1, 0));
+
+        fish.swim(&screen(), &config, 0, &mut rng);
+        let baseline = fish.upper_left.y - fish.bob.unwrap();
+        let mut heights = Vec::new();
+        for frame in 1..64 {
+            fish.swim(&screen(), &config, frame, &mut rng);
+            let bob = fish.bob.unwrap();
+            assert_eq!(fish.upper_left.y - bob, baseline);
+            assert!(bob.abs() <= fish.bob_amplitude);
+            heights.push(fish.upper_left.y);
+        }
+        assert!(heights.iter().min() < heights.iter().max());
+    }
+
+    #[test]
+    fn bubbles_behind_or_in_front() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig { bubble_count: 1, ..config(1) };
+        let mut tank = tank_of(&data, config);
+        place(&mut tank, 0, Point::new(20, 20), Point::new(1, 0));
+        tank.bubbles[0].center = Point::new(21, 21);
+        tank.bubbles[0].radius = 2;
+        // One point on the bubble is over the fish, and one isn't.
+        let shared = Point::new(21, 23);
+        let water = Point::new(21, 19);
+
+        assert_eq!(opaque(tank.get_point(&shared)), Some(RED));
+        assert_eq!(opaque(tank.get_point(&water)), Some(BUBBLE_COLOR));
+
+        tank.config.bubbles_in_front = true;
+        assert_eq!(opaque(tank.get_point(&shared)),
+                   Some(blend565(BUBBLE_COLOR, RED, BUBBLE_ALPHA)));
+        assert_eq!(opaque(tank.get_point(&water)), Some(BUBBLE_COLOR));
+    }
+
+    #[test]
+    fn blend_extremes_and_middle() {
+        assert_eq!(blend565(WHITE, 0x0000, 0), 0x0000);
+        assert_eq!(blend565(WHITE, 0x0000, 255), WHITE);
+        assert_eq!(blend565(WHITE, 0x0000, 128), rgb(15, 31, 15));
+        assert_eq!(blend565(RED, BLUE, 128), rgb(15, 0, 16));
+    }
+
+    #[test]
+    fn runs_rebuild_the_image() {
+        let mut tank = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        tank.swim();
+        let all = Rectangle::new(Point::new(0, 0),
+                                 Point::new(cvt(WIDTH) - 1, cvt(HEIGHT) - 1));
+        let mut pixels = vec![0; (WIDTH * HEIGHT) as usize];
+        let mut runs = 0;
+        for (start, color, len) in RunIterator::new(
+            TankIterator::for_rect(&tank, all)) {
+            let n = (start.x as u32 + start.y as u32 * WIDTH) as usize;
+            pixels[n..n + len as usize].fill(color);
+            runs += 1;
+        }
+        assert!(pixels == snapshot(&tank));
+        assert!(runs < pixels.len() / 4);
+    }
+
+    #[test]
+    fn restored_tank_carries_on_the_same() {
+        let mut saved = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        for _ in 0..3 {
+            saved.swim();
+        }
+        let state = saved.save_state();
+        let mut restored = FishTank::new(screen(), &SPRITE_DATA.0, 1).unwrap();
+        restored.restore_state(&state).unwrap();
+        for _ in 0..5 {
+            assert!(snapshot(&restored) == snapshot(&saved));
+            restored.swim();
+            saved.swim();
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "save-state")]
+    fn serialized_state_carries_on_the_same() {
+        let mut saved = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        for _ in 0..3 {
+            saved.swim();
+        }
+        let bytes = postcard::to_allocvec(&saved.save_state()).unwrap();
+        let state: TankState = postcard::from_bytes(&bytes).unwrap();
+        let mut restored = FishTank::new(screen(), &SPRITE_DATA.0, 1).unwrap();
+        restored.restore_state(&state).unwrap();
+        for _ in 0..5 {
+            assert!(snapshot(&restored) == snapshot(&saved));
+            restored.swim();
+            saved.swim();
+        }
+    }
+
+    #[test]
+    fn reshuffle_changes_species_but_not_positions() {
+        let mut tank = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        let before: Vec<_> = tank.fish[..tank.num_fish].iter()
+            .map(|fish| (fish.upper_left, fish.species))
+            .collect();
+        tank.reshuffle_species();
+        let after: Vec<_> = tank.fish[..tank.num_fish].iter()
+            .map(|fish| (fish.upper_left, fish.species))
+            .collect();
+        assert!(before.iter().zip(&after).all(|(b, a)| b.0 == a.0));
+        assert!(before.iter().zip(&after).any(|(b, a)| b.1 != a.1));
+    }
+
+    #[test]
+    fn intro_finishes() {
+        let mut tank = FishTankBuilder::new(&SPRITE_DATA.0)
+            .intro(0x0000)
+            .build(screen())
+            .unwrap();
+        let mut frames = 0;
+        while tank.intro_frame() {
+            frames += 1;
+            assert!(frames < 100);
+        }
+        assert!(frames > 0);
+
+        let mut plain = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        assert!(!plain.intro_frame());
+    }
+
+    #[test]
+    fn streams_diverge() {
+        let tank = |stream| {
+            FishTankBuilder::new(&SPRITE_DATA.0)
+                .stream(stream)
+                .build(screen())
+                .unwrap()
+        };
+        let mut a = tank(1);
+        let mut b = tank(2);
+        let mut diverged = snapshot(&a) != snapshot(&b);
+        for _ in 0..3 {
+            a.swim();
+            b.swim();
+            diverged |= snapshot(&a) != snapshot(&b);
+        }
+        assert!(diverged);
+    }
+
+    #[test]
+    fn randomized_fish_are_on_screen_whatever_their_size() {
+        let vertical = TankConfig {
+            speed_range: 0..1,
+            drift_range: -2..3,
+            ..TankConfig::default()
+        };
+        let configs = [TankConfig::default(), vertical];
+        let sizes = [(1, 1), (12, 10), (200, 10), (10, 200), (300, 300)];
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        for &(width, height) in &sizes {
+            let data = solid(width, height, RED);
+            let mut fish = lone_fish(&data);
+            for config in &configs {
+                for _ in 0..50 {
+                    fish.randomize(&screen(), config, &mut rng);
+                    assert!(fish.on_screen(&screen()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bands_match_looking_at_every_fish() {
+        let mut tank = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        for _ in 0..10 {
+            tank.swim();
+            for y in 0..cvt(HEIGHT) {
+                for x in 0..cvt(WIDTH) {
+                    let pt = Point::new(x, y);
+                    assert_eq!(opaque(tank.scene_point(&pt, true)),
+                               opaque(tank.point_behind(&pt, u32::MAX, true)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn frames_are_the_same_with_every_band_full() {
+        let mut tank = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        for _ in 0..10 {
+            tank.swim();
+            let banded = snapshot(&tank);
+            let bands = tank.bands;
+            tank.bands = [u32::MAX; NUM_BANDS];
+            assert!(snapshot(&tank) == banded);
+            tank.bands = bands;
+        }
+    }
+
+    #[test]
+    fn three_sprite_sheet() {
+        let (red, green, blue) = ([RED], [GREEN], [BLUE]);
+        let data = sheet::<NUM_FRAMES>(&[(1, 1, &[&red]),
+                                         (1, 1, &[&green]),
+                                         (1, 1, &[&blue])]);
+        assert!(DefaultSprite::make_sprite(3, &data).err() ==
+                Some(SpriteError::NoSuchSprite));
+
+        let mut tank = tank_of(&data, TankConfig::default());
+        assert_eq!(tank.sprite_count(), 3);
+        for _ in 0..3 {
+            tank.reshuffle_species();
+            assert!(tank.fish[..tank.num_fish].iter()
+                    .all(|fish| fish.species < 3));
+        }
+    }
+
+    #[test]
+    fn hovering_fish_stays_put() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig { hover_odds: 1, ..TankConfig::default() };
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        let mut fish = lone_fish(&data);
+        place_fish(&mut fish, Point::new(50, 30), Point::new(1, 0));
+
+        fish.swim(&screen(), &config, 0, &mut rng);
+        let hover = fish.hover_frames;
+        assert!(HOVER_FRAMES.contains(&hover));
+        let x = fish.upper_left.x;
+        assert_eq!(x, 50);
+        for frame in 1..u32::from(hover) {
+            fish.swim(&screen(), &config, frame, &mut rng);
+            assert_eq!(fish.upper_left.x, x);
+        }
+    }
+
+    #[test]
+    fn mouth_opens_once_per_cycle() {
+        let frames: [&[u16]; 3] = [&[RED; 4], &[GREEN; 4], &[BLUE; 4]];
+        let data = sheet::<NUM_FRAMES>(&[(2, 2, &frames)]);
+        let mut tank = tank_of(&data, config(1));
+        place(&mut tank, 0, Point::new(20, 30), Point::new(1, 0));
+        tank.fish[0].animation = 0;
+        tank.fish[0].animation_speed = 2;
+
+        // The mouth opens each time the fish moves on to frame 2, once
+        // every 3 frames of 2 steps each.
+        let mut opened = 0;
+        for _ in 0..60 {
+            tank.swim();
+            opened += tank.events()
+                .filter(|&event| event == Event::MouthOpen(0))
+                .count();
+        }
+        assert_eq!(opened, 10);
+    }
+
+    #[test]
+    fn sprites_from_two_sheets() {
+        let red = solid(1, 1, RED);
+        let green = solid(1, 1, GREEN);
+        let sheets = [red.as_byte_slice(), green.as_byte_slice()];
+        let tank = FishTankBuilder::new(&[])
+            .sheets(&sheets)
+            .fish_count(2)
+            .build(screen())
+            .unwrap();
+        assert_eq!(tank.sprite_count(), 2);
+        let at = |n: usize| {
+            opaque(tank.sprites[n].get_point(&Point::new(0, 0), 0,
+                                             FULL_SCALE))
+        };
+        assert_eq!(at(0), Some(RED));
+        assert_eq!(at(1), Some(GREEN));
+    }
+
+    #[test]
+    fn three_pixel_move_leaves_no_trail() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig { speed_range: 3..4, ..config(1) };
+        let mut tank = FishTank::with_rng(screen(), data.as_byte_slice(),
+                                          zeros(), config).unwrap();
+        let start = tank.fish[0].upper_left;
+        assert_no_trail(&mut tank, 5);
+        assert_eq!(tank.fish[0].upper_left, start + Point::new(15, 0));
+    }
+
+    #[test]
+    fn positions_match_the_fish() {
+        let mut tank = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        tank.remove_fish();
+        tank.swim();
+        assert_eq!(tank.fish_positions().count(), tank.num_fish());
+        for ((upper_left, size, direction), fish) in tank.fish_positions()
+            .zip(&tank.fish) {
+            assert_eq!(upper_left, fish.upper_left);
+            assert_eq!(size, fish.size);
+            assert!(direction == fish.direction);
+        }
+    }
+
+    #[test]
+    fn debug_box_corners() {
+        let data = solid(6, 4, RED);
+        let config = TankConfig { debug_boxes: true, ..config(1) };
+        let mut tank = tank_of(&data, config);
+        place(&mut tank, 0, Point::new(10, 10), Point::new(1, 0));
+        let pixels = snapshot(&tank);
+        for &(x, y) in &[(10, 10), (15, 10), (10, 13), (15, 13)] {
+            assert_eq!(pixel_at(&pixels, x, y), DEBUG_BOX_COLOR);
+        }
+        assert_eq!(pixel_at(&pixels, 12, 11), RED);
+    }
+
+    #[test]
+    fn conversions_clamp() {
+        assert_eq!(cvt(0), 0);
+        assert_eq!(cvt(i32::MAX as u32), i32::MAX);
+        assert_eq!(cvt(i32::MAX as u32 + 1), i32::MAX);
+        assert_eq!(cvt(u32::MAX), i32::MAX);
+        assert_eq!(to_size(-1), 0);
+        assert_eq!(to_size(i32::MIN), 0);
+        assert_eq!(to_size(i32::MAX), i32::MAX as u32);
+        assert_eq!(to_index(-1), 0);
+        assert_eq!(to_index(7), 7);
+        assert_eq!(size_index(u32::MAX), u32::MAX as usize);
+    }
+
+    #[test]
+    fn acceleration_ramps_up() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig { acceleration: 2, ..TankConfig::default() };
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        let mut fish = lone_fish(&data);
+        place_fish(&mut fish, Point::new(20, 30), Point::new(1, 0));
+        // Going a pixel a step three quarters of the time is 12/16 of a
+        // pixel a step, which takes 6 steps to get to.
+        for expected in [2, 4, 6, 8, 10, 12, 12, 12] {
+            fish.swim(&screen(), &config, 0, &mut rng);
+            assert_eq!(fish.glide.x, expected);
+        }
+    }
+
+    #[test]
+    fn fish_leaving_the_right_edge() {
+        let data = solid(4, 4, RED);
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        let mut fish = lone_fish(&data);
+        let edge = cvt(WIDTH);
+
+        let wrap = TankConfig {
+            edge_behavior: EdgeBehavior::Wrap,
+            ..TankConfig::default()
+        };
+        place_fish(&mut fish, Point::new(edge, 40), Point::new(5, 0));
+        while fish.upper_left.x == edge {
+            assert!(!fish.swim(&screen(), &wrap, 0, &mut rng));
+        }
+        assert_eq!(fish.upper_left.x, -4);
+        assert!((fish.upper_left.y - 40).abs() <= 1);
+        assert!(fish.direction == Dir::Right);
+
+        place_fish(&mut fish, Point::new(edge, 40), Point::new(5, 0));
+        let randomize = TankConfig::default();
+        while fish.upper_left.x == edge {
+            if fish.swim(&screen(), &randomize, 0, &mut rng) {
+                break;
+            }
+        }
+        assert!(fish.upper_left.x == -4 || fish.upper_left.x == edge);
+        assert_eq!(fish.speed, 1);
+    }
+
+    #[test]
+    fn vector_helpers() {
+        assert_eq!(clamp_axes(Point::new(5, -9), 3), Point::new(3, -3));
+        assert_eq!(clamp_axes(Point::new(1, -2), 3), Point::new(1, -2));
+        assert_eq!(clamp_axes(Point::new(-7, 2), 3), Point::new(-3, 2));
+        // A diagonal step stays diagonal, even at 1 pixel a step.
+        assert_eq!(clamp_axes(Point::new(4, 4), 1), Point::new(1, 1));
+        assert_eq!(clamp_axes(Point::new(-6, 6), 2), Point::new(-2, 2));
+        assert_eq!(div_floor(Point::new(-1, 17), 16), Point::new(-1, 1));
+        assert_eq!(div_floor(Point::new(-16, -17), 16), Point::new(-1, -2));
+        assert_eq!(length2(Point::new(3, -4)), 25);
+    }
+
+    #[test]
+    fn jitter_stays_in_the_water() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig::default();
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        let mut fish = lone_fish(&data);
+        for &y in &[0, cvt(HEIGHT) - 4] {
+            place_fish(&mut fish, Point::new(20, y), Point::new(0, 0));
+            for frame in 0..200 {
+                fish.swim(&screen(), &config, frame, &mut rng);
+                assert!((0..=cvt(HEIGHT) - 4).contains(&fish.upper_left.y));
+            }
+        }
+    }
+
+    #[test]
+    fn current_carries_fish() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig {
+            current:     Point::new(8, 0),
+            speed_range: 0..1,
+            drift_range: 0..1,
+            ..TankConfig::default()
+        };
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        let mut fish = lone_fish(&data);
+        place_fish(&mut fish, Point::new(20, 30), Point::new(0, 0));
+        for frame in 0..20 {
+            fish.swim(&screen(), &config, frame, &mut rng);
+        }
+        // Half a pixel a step.
+        assert_eq!(fish.upper_left.x, 30);
+    }
+
+    #[test]
+    fn fish_reverse() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig { reverse_odds: 1, ..TankConfig::default() };
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        let mut fish = lone_fish(&data);
+        place_fish(&mut fish, Point::new(60, 30), Point::new(1, 0));
+        for frame in 0..10 {
+            let direction = fish.direction;
+            fish.swim(&screen(), &config, frame, &mut rng);
+            assert!(fish.direction != direction);
+        }
+    }
+
+    #[test]
+    fn tiled_background() {
+        let tile = [0x0000, WHITE, WHITE, 0x0000];
+        let tank = FishTankBuilder::new(&SPRITE_DATA.0)
+            .fish_count(0)
+            .tile(2, &tile)
+            .build(screen())
+            .unwrap();
+        let pixels = snapshot(&tank);
+        for y in 0..4 {
+            for x in 0..4 {
+                let expected = if (x + y) % 2 == 0 { 0x0000 } else { WHITE };
+                assert_eq!(pixel_at(&pixels, x, y), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn stratified_fish_spread_out() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig {
+            spawn: SpawnStrategy::Stratified,
+            ..config(4)
+        };
+        let tank = tank_of(&data, config);
+        // One fish in each quarter of the screen, from top to bottom.
+        let mut rows: Vec<_> = tank.fish_positions()
+            .map(|(upper_left, _, _)| upper_left.y * 4 / cvt(HEIGHT))
+            .collect();
+        rows.sort_unstable();
+        assert_eq!(rows, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn render_to_slice_matches_draw_target() {
+        let tank = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        let mut fb = FrameBuffer::new(screen());
+        tank.render_all(&mut fb).unwrap();
+        assert!(snapshot(&tank) == fb.pixels);
+
+        let mut small = vec![0; (WIDTH * HEIGHT) as usize - 1];
+        assert_eq!(tank.render_to_slice(&mut small, WIDTH as usize),
+                   Err(TankError::BufferTooSmall));
+    }
+
+    #[test]
+    fn hashed_rows_are_drawn_in_pieces() {
+        let mut tank = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        let mut fb = FrameBuffer::new(screen());
+        let mut row_hashes = [0; HEIGHT as usize];
+        // Narrower than the screen, and not a factor of its width.
+        let mut line_buf = [rgb565(0); 7];
+        for _ in 0..3 {
+            tank.render_hashed(&mut PixelRows(&mut fb), &mut row_hashes,
+                               &mut line_buf).unwrap();
+            assert!(fb.pixels == snapshot(&tank));
+            tank.swim();
+        }
+    }
+
+    #[test]
+    fn animation_modes() {
+        let frames: [&[u16]; 3] = [&[RED], &[GREEN], &[BLUE]];
+        let data = sheet::<NUM_FRAMES>(&[(1, 1, &frames)]);
+        let mut fish = lone_fish(&data);
+        fish.animation_speed = 1;
+        let frames = |fish: &mut Fish<'_>, mode| {
+            fish.animation_mode = mode;
+            fish.animation = 0;
+            (0..8).map(|_| { fish.animate(); fish.frame() })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(frames(&mut fish, AnimationMode::Loop),
+                   [1, 2, 0, 1, 2, 0, 1, 2]);
+        assert_eq!(frames(&mut fish, AnimationMode::PingPong),
+                   [1, 2, 1, 0, 1, 2, 1, 0]);
+    }
+
+    #[test]
+    fn haze_extremes() {
+        assert_eq!(haze(RED, BLUE, 0), RED);
+        assert_eq!(haze(RED, BLUE, 255), BLUE);
+    }
+
+    #[test]
+    fn old_fish_are_replaced() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig { lifespan: 5..6, ..TankConfig::default() };
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        let mut fish = lone_fish(&data);
+        fish.randomize(&screen(), &config, &mut rng);
+        assert_eq!(fish.max_age, 5);
+        let mut replaced = false;
+        for frame in 0..400 {
+            if fish.swim(&screen(), &config, frame, &mut rng) {
+                replaced = true;
+                break;
+            }
+            assert!(frame >= 4 || !fish.leaving());
+        }
+        assert!(replaced);
+        assert_eq!(fish.age, 0);
+    }
+
+    #[test]
+    fn frame_offset_0_reuses_frame_0() {
+        let frames: [&[u16]; 3] = [&[RED], &[GREEN], &[BLUE]];
+        let mut data = sheet::<NUM_FRAMES>(&[(1, 1, &frames)]);
+        data[FRAME_OFFSETS + 2] = 0;
+        data[FRAME_OFFSETS + 3] = 0;
+        let sprite = DefaultSprite::make_sprite(0, &data).unwrap();
+        let at = |frame| {
+            opaque(sprite.get_point(&Point::new(0, 0), frame, FULL_SCALE))
+        };
+        assert_eq!(at(0), Some(RED));
+        assert_eq!(at(1), Some(RED));
+        assert_eq!(at(2), Some(BLUE));
+
+        data[FRAME_OFFSETS + 4] = data.len() as u16;
+        assert!(DefaultSprite::make_sprite(0, &data).err() ==
+                Some(SpriteError::FrameOutOfRange));
+    }
+
+    #[test]
+    fn distance_animation_waits_for_movement() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig {
+            animation_timing: AnimationTiming::Distance,
+            speed_range: 0..1,
+            drift_range: 0..1,
+            ..TankConfig::default()
+        };
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        let mut fish = lone_fish(&data);
+        fish.randomize(&screen(), &config, &mut rng);
+        place_fish(&mut fish, Point::new(20, 30), Point::new(0, 0));
+        let animation = fish.animation;
+        for frame in 0..20 {
+            fish.swim(&screen(), &config, frame, &mut rng);
+            if fish.upper_left == fish.prev_upper_left {
+                assert_eq!(fish.animation, animation);
+            }
+        }
+    }
+
+    #[test]
+    fn upscaled_pixels_are_blocks() {
+        let pixels = [RED, GREEN, BLUE, WHITE];
+        let data = sheet::<NUM_FRAMES>(&[(2, 2, &[&pixels])]);
+        let config = TankConfig { upscale: 2, ..config(1) };
+        let mut tank = tank_of(&data, config);
+        place(&mut tank, 0, Point::new(10, 10), Point::new(1, 0));
+        let big = snapshot(&tank);
+        for (n, &color) in pixels.iter().enumerate() {
+            let (x, y) = (20 + 2 * (n as u32 % 2), 20 + 2 * (n as u32 / 2));
+            for &(dx, dy) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+                assert_eq!(pixel_at(&big, x + dx, y + dy), color);
+            }
+        }
+    }
+
+    #[test]
+    fn fish_count_is_capped() {
+        let mut tank = FishTankBuilder::new(&SPRITE_DATA.0)
+            .fish_count(MAX_FISH + 5)
+            .build(screen())
+            .unwrap();
+        assert_eq!(tank.num_fish(), MAX_FISH);
+        assert!(!tank.add_fish());
+        for n in (0..MAX_FISH).rev() {
+            assert!(tank.remove_fish());
+            assert_eq!(tank.num_fish(), n);
+        }
+        assert!(!tank.remove_fish());
+        assert!(tank.add_fish());
+        assert_eq!(tank.num_fish(), 1);
+
+        for _ in 0..MAX_FOOD {
+            assert!(tank.drop_food(Point::new(10, 10)));
+        }
+        assert!(!tank.drop_food(Point::new(10, 10)));
+    }
+
+    #[test]
+    fn keep_animation_phase_after_randomizing() {
+        let frames: [&[u16]; 3] = [&[RED], &[GREEN], &[BLUE]];
+        let data = sheet::<NUM_FRAMES>(&[(1, 1, &frames)]);
+        let config = TankConfig {
+            keep_animation_phase: true,
+            ..TankConfig::default()
+        };
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        let mut fish = lone_fish(&data);
+        fish.randomize(&screen(), &config, &mut rng);
+        place_fish(&mut fish, Point::new(cvt(WIDTH), 40), Point::new(5, 0));
+        fish.animation_speed = 2;
+        fish.animation = 1;
+        let mut expected = fish;
+        loop {
+            expected.animate();
+            if fish.swim(&screen(), &config, 0, &mut rng) {
+                break;
+            }
+        }
+        assert_eq!(fish.animation_speed, 2);
+        assert_eq!(fish.animation, expected.animation);
+    }
+
+    #[test]
+    fn overlay_is_on_top() {
+        let data = solid(4, 4, RED);
+        let mut tank = tank_of(&data, config(1));
+        place(&mut tank, 0, Point::new(10, 10), Point::new(1, 0));
+        let pixels = [GREEN, TRANSPARENT];
+        let overlay = sheet::<NUM_FRAMES>(&[(2, 1, &[&pixels])]);
+        let overlay = DefaultSprite::make_sprite(0, &overlay).unwrap();
+        tank.set_overlay(overlay, Point::new(11, 11));
+
+        assert_eq!(opaque(tank.get_point(&Point::new(11, 11))), Some(GREEN));
+        assert_eq!(opaque(tank.get_point(&Point::new(12, 11))), Some(RED));
+        tank.clear_overlay();
+        assert_eq!(opaque(tank.get_point(&Point::new(11, 11))), Some(RED));
+    }
+
+    #[test]
+    fn sprite_area_filters_sprites() {
+        let small = [RED; 16];
+        let big = [GREEN; 64];
+        let data = sheet::<NUM_FRAMES>(&[(4, 4, &[&small]),
+                                         (8, 8, &[&big])]);
+        let small_only = TankConfig { sprite_area: 10..20, ..config(4) };
+        let tank = tank_of(&data, small_only);
+        for (_, size, _) in tank.fish_positions() {
+            assert_eq!(size, Size::new(4, 4));
+        }
+
+        let neither = TankConfig { sprite_area: 100..200, ..config(4) };
+        let tank = FishTank::with_config(screen(), data.as_byte_slice(), SEED,
+                                         neither);
+        assert!(tank.err() == Some(TankError::NoSpriteInRange));
+    }
+
+    #[test]
+    fn gray_and_binary_targets() {
+        assert_eq!(luma565(0x0000), 0);
+        assert_eq!(luma565(WHITE), 255);
+        assert_eq!(luma565(RED), 76);
+        assert_eq!(luma565(GREEN), 150);
+        assert_eq!(luma565(BLUE), 29);
+
+        let green = Pixel(Point::new(0, 0), Rgb565::from(RawU16::new(GREEN)));
+        let mut display = OnePixel(None);
+        GrayTarget(&mut display).draw_pixel(green).unwrap();
+        assert_eq!(display.0, Some(Gray8::new(150)));
+
+        for &(threshold, expected) in &[(150, BinaryColor::On),
+                                        (151, BinaryColor::Off)] {
+            let mut display = OnePixel(None);
+            BinaryTarget { target: &mut display, threshold }
+                .draw_pixel(green)
+                .unwrap();
+            assert_eq!(display.0, Some(expected));
+        }
+    }
+
+    #[test]
+    fn direction_bias() {
+        let pixels = [RED; 16];
+        let data = sheet::<NUM_FRAMES>(&[(4, 4, &[&pixels]),
+                                         (4, 4, &[&pixels])]);
+        let config = TankConfig {
+            species:        &[(0, 1), (1, 1)],
+            direction_bias: &[0, 255],
+            ..config(MAX_FISH)
+        };
+        let mut tank = tank_of(&data, config);
+        for seed in 0..10 {
+            tank.reset(seed);
+            for fish in &tank.fish[..tank.num_fish] {
+                let right = fish.species == 1;
+                assert!((fish.direction == Dir::Right) == right);
+            }
+        }
+    }
+
+    #[test]
+    fn only_live_fish_are_looked_at() {
+        let config = TankConfig { fish_count: 3, ..TankConfig::default() };
+        let mut tank = FishTank::with_config(screen(), &SPRITE_DATA.0, SEED,
+                                             config).unwrap();
+        assert_eq!(tank.num_fish(), 3);
+        let before = snapshot(&tank);
+
+        // Spare fish spread over the whole screen change nothing, since
+        // only the first three are ever drawn or moved.
+        for i in 3..MAX_FISH {
+            let at = Point::new(cvt(i as u32) * 8, cvt(i as u32) * 4 % 80);
+            place(&mut tank, i, at, Point::new(1, 0));
+        }
+        assert!(snapshot(&tank) == before);
+        let spare: Vec<_> = tank.fish[3..].iter().map(fish_state).collect();
+        for _ in 0..5 {
+            tank.swim();
+        }
+        assert!(tank.fish[3..].iter().map(fish_state).eq(spare));
+        assert_eq!(tank.fish_positions().count(), 3);
+    }
+
+    #[test]
+    fn four_flips() {
+        // Each corner of the sprite is a different color.
+        let pixels = [RED, GREEN, BLUE, WHITE];
+        let data = sheet::<NUM_FRAMES>(&[(2, 2, &[&pixels])]);
+        let mut tank = tank_of(&data, config(1));
+        let corner = Point::new(10, 10);
+        let cases = [
+            (Dir::Right, false, RED),
+            (Dir::Left,  false, GREEN),
+            (Dir::Right, true,  BLUE),
+            (Dir::Left,  true,  WHITE),
+        ];
+        for &(direction, flip_v, color) in &cases {
+            let x = if direction == Dir::Left { -1 } else { 1 };
+            place(&mut tank, 0, corner, Point::new(x, 0));
+            assert!(tank.set_flip_v(0, flip_v));
+            assert_eq!(opaque(tank.get_point(&corner)), Some(color));
+        }
+        assert!(!tank.set_flip_v(1, true));
+    }
+
+    #[test]
+    fn layout_hash_follows_the_seed() {
+        let tank = |seed| {
+            FishTank::new(screen(), &SPRITE_DATA.0, seed).unwrap()
+        };
+        let (mut a, mut b, mut c) = (tank(SEED), tank(SEED), tank(1));
+        for _ in 0..5 {
+            assert_eq!(a.layout_hash(), b.layout_hash());
+            assert_ne!(a.layout_hash(), c.layout_hash());
+            a.swim();
+            b.swim();
+            c.swim();
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn built_sprites_read_back() {
+        use sprite_build::{SpriteImage, build_sprites, compress_sprites};
+
+        let still = [RED, TRANSPARENT, GREEN, BLUE, BLUE, WHITE];
+        let frames: [&[u16]; 2] = [&[RED, GREEN], &[GREEN, RED]];
+        let images = [
+            SpriteImage { width: 3, height: 2, transparent: TRANSPARENT,
+                          frames: &[&still] },
+            SpriteImage { width: 1, height: 2, transparent: TRANSPARENT,
+                          frames: &frames },
+        ];
+        let built = build_sprites(&images).unwrap();
+        let compressed = compress_sprites(&built).unwrap();
+        for data in [&built, &compressed] {
+            assert_eq!(sprite_count(data), images.len());
+            for (n, image) in images.iter().enumerate() {
+                let sprite = DefaultSprite::make_sprite(n, data).unwrap();
+                assert_eq!(usize::from(sprite.frame_count()),
+                           image.frames.len());
+                for (frame_no, frame) in (0..).zip(image.frames) {
+                    for (i, &c) in frame.iter().enumerate() {
+                        let x = (i % usize::from(image.width)) as i32;
+                        let y = (i / usize::from(image.width)) as i32;
+                        let pv = sprite.get_point(&Point::new(x, y), frame_no,
+                                                  FULL_SCALE);
+                        let expected = Some(c).filter(|&c| c != TRANSPARENT);
+                        assert_eq!(opaque(pv), expected);
+                    }
+                }
+            }
+        }
+
+        let empty = SpriteImage { width: 2, height: 2,
+                                  transparent: TRANSPARENT, frames: &[] };
+        assert!(build_sprites(&[empty]).err() ==
+                Some(SpriteError::BadFrameCount));
+    }
+
+    #[test]
+    fn screenshot_reads_back() {
+        let tank = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        let pixels = snapshot(&tank);
+        let ppm: Vec<u8> = ppm_bytes(&pixels, WIDTH as usize).collect();
+
+        let header = b"P6\n160 80\n255\n";
+        assert_eq!(&ppm[..header.len()], header);
+        let bytes = &ppm[header.len()..];
+        assert_eq!(bytes.len(), 3 * pixels.len());
+        for (&p, bytes) in pixels.iter().zip(bytes.chunks(3)) {
+            let (r, g, b) = (u16::from(bytes[0]), u16::from(bytes[1]),
+                             u16::from(bytes[2]));
+            assert_eq!(rgb(r >> 3, g >> 2, b >> 3), p);
+        }
+
+        assert_eq!(rgb888(WHITE), [255, 255, 255]);
+        assert_eq!(rgb888(RED), [255, 0, 0]);
+        let one: Vec<u8> = ppm_bytes(&[0x0000; 3], 1).collect();
+        assert_eq!(one, b"P6\n1 3\n255\n\0\0\0\0\0\0\0\0\0");
+    }
+
+    #[test]
+    fn screenshot_a_row_at_a_time() {
+        let tank = FishTank::new(screen(), &SPRITE_DATA.0, SEED).unwrap();
+        let pixels = snapshot(&tank);
+        let whole: Vec<u8> = ppm_bytes(&pixels, WIDTH as usize).collect();
+
+        let mut rows: Vec<u8> = ppm_header(WIDTH as usize, HEIGHT as usize)
+            .collect();
+        let mut line_buf = [rgb565(0); WIDTH as usize];
+        for y in 0..HEIGHT {
+            tank.render_row(y, &mut line_buf).unwrap();
+            rows.extend(line_buf.iter()
+                        .flat_map(|&c| rgb888(RawU16::from(c).into_inner())));
+        }
+        assert!(rows == whole);
+
+        let mut short = [rgb565(0); WIDTH as usize - 1];
+        assert_eq!(tank.render_row(0, &mut short),
+                   Err(TankError::BufferTooSmall));
+    }
+
+    #[test]
+    fn current_cannot_push_bouncing_fish_off_the_edge() {
+        let data = solid(4, 4, RED);
+        let config = TankConfig {
+            edge_behavior: EdgeBehavior::Bounce,
+            current:       Point::new(12, 0),
+            ..TankConfig::default()
+        };
+        let mut rng = Pcg32::new(SEED, DEFAULT_STREAM);
+        let mut fish = lone_fish(&data);
+        let edge = cvt(WIDTH) - 4;
+        place_fish(&mut fish, Point::new(edge - 1, 40), Point::new(1, 0));
+        for frame in 0..20 {
+            fish.swim(&screen(), &config, frame, &mut rng);
+            assert!(fish.upper_left.x <= edge);
+        }
+        assert!(fish.velocity.x < 0);
+        assert!(fish.direction == Dir::Left);
+    }
+}
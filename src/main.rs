@@ -2,301 +2,398 @@
 #![no_main]
 
 use panic_halt as _;
+#[cfg(feature = "defmt-log")]
+use defmt_rtt as _;
 
+use core::convert::TryInto;
+use core::fmt::Write;
+
+#[cfg(not(feature = "fixed-seed"))]
+use gd32vf103xx_hal::adc::Adc;
+#[cfg(feature = "low-power")]
+use gd32vf103xx_hal::eclic::{EclicExt, Level, Priority, TriggerType};
 use gd32vf103xx_hal::pac;
 use gd32vf103xx_hal::prelude::*;
+use gd32vf103xx_hal::serial::{Config, Serial};
+#[cfg(feature = "sd-sprites")]
+use gd32vf103xx_hal::spi::{MODE_0, Spi};
+use gd32vf103xx_hal::timer::Timer;
+#[cfg(feature = "low-power")]
+use gd32vf103xx_hal::timer::Event;
 use longan_nano::{lcd, lcd_pins};
 use riscv_rt::entry;
 
-use byte_slice_cast::AsSliceOf;
-use core::convert::TryInto;
-use embedded_graphics::drawable::Pixel;
-use embedded_graphics::geometry::{Point, Size};
-use embedded_graphics::pixelcolor::Rgb565;
-use embedded_graphics::pixelcolor::raw::RawU16;
-use embedded_graphics::prelude::DrawTarget;
-use rand::Rng;
-use rand_pcg::Pcg32;
-
-// Number of fish on the screen at once.  Does not have to equal NUM_SPRITES.
-const NUM_FISH: usize = 10;
-
-// For the two fish that are animated, controls how fast their mouths
-// open and close.  Larger is slower.  1 is fastest.
-const ANIMATION_SPEED: u8 = 2;
-
-// Color of the water, in RGB565 format.
-const BACKGROUND: u16 = 0x1f;   // blue
-
-// This is for making sure that the area around the fish gets erased.
-// As long as the fish don't move by more than one pixel at a time,
-// 1 should be sufficient.
-const FUDGE_FACTOR: i32 = 1;
-
-// These three constants are baked into fish.raw, so don't change them
-// unless fish.raw changes.
-const NUM_FRAMES: usize = 3;
-const NUM_SPRITES: usize = 10;
-const TRANSPARENT: u16 = 0xdead;
+use embedded_graphics::pixelcolor::{Rgb565, RgbColor};
+use embedded_graphics::pixelcolor::raw::{RawData, RawU16};
+use embedded_graphics::prelude::{DrawTarget, Point};
+#[cfg(not(feature = "fixed-seed"))]
+use embedded_hal::adc::OneShot;
+use embedded_hal::digital::v2::InputPin;
+use embedded_hal::serial::Read;
+use embedded_hal::timer::CountDown;
+#[cfg(feature = "sd-sprites")]
+use embedded_sdmmc::{
+    Controller,
+    Mode,
+    SdMmcSpi,
+    TimeSource,
+    Timestamp,
+    VolumeIdx,
+};
+#[cfg(feature = "fixed-seed")]
+use rs_embedded_fish::DEFAULT_STREAM;
+use rs_embedded_fish::{FishTankBuilder, LineTarget, rgb565};
+#[cfg(feature = "shadow-framebuffer")]
+use rs_embedded_fish::ppm_bytes;
+#[cfg(not(feature = "shadow-framebuffer"))]
+use rs_embedded_fish::{DefaultFishTank, ppm_header, rgb888};
+
+// The sprite data is read as u16 words, so it has to be 2-byte aligned,
+// which include_bytes! doesn't promise on its own.
+#[repr(align(2))]
+struct Aligned<T: ?Sized>(T);
 
 // This file contains the fish images.
-const SPRITE_DATA: &[u8] = include_bytes!("fish.raw");
-
-enum PointValue {
-    OutOfRange,
-    Transparent,
-    Opaque(u16),
-}
-
-#[derive(PartialEq, Copy, Clone)]
-enum Dir {
-    Left,
-    Right,
+static SPRITE_DATA: &Aligned<[u8]> = &Aligned(*include_bytes!("fish.raw"));
+
+// The SD card's sprite data is read into a buffer on the stack, which
+// doesn't leave room for the shadow framebuffer as well.
+#[cfg(all(feature = "sd-sprites", feature = "shadow-framebuffer"))]
+compile_error!("sd-sprites and shadow-framebuffer don't both fit in RAM");
+
+// Largest FISH.RAW which can be loaded from the SD card.  The built-in
+// fish.raw is about 21 KB.
+#[cfg(feature = "sd-sprites")]
+const SD_SPRITE_MAX: usize = 24 * 1024;
+
+// Reasons why the sprite data couldn't be loaded from the SD card.
+#[cfg(feature = "sd-sprites")]
+enum SdError {
+    NoCard,
+    NoFile,
+    TooBig,
 }
 
-#[derive(Copy, Clone)]
-struct Sprite<'a> {
-    size: Size,
-    frames: [&'a [u16]; NUM_FRAMES],
-}
-
-#[derive(Copy, Clone)]
-struct Fish<'a> {
-    fish_type:  Sprite<'a>,
-    upper_left: Point,
-    size:       Size,
-    direction:  Dir,
-    animation:  u8,
+#[cfg(feature = "sd-sprites")]
+impl SdError {
+    fn message(&self) -> &'static str {
+        match self {
+            SdError::NoCard => "no SD card",
+            SdError::NoFile => "no FISH.RAW on the SD card",
+            SdError::TooBig => "FISH.RAW is too big",
+        }
+    }
 }
 
-struct FishTank<'a> {
-    fish:    [Fish<'a>;   NUM_FISH],
-    size:    Size,
-    rng:     Pcg32,
+// Files are only read, so the SD card has no use for the time.
+#[cfg(feature = "sd-sprites")]
+struct NoClock;
+
+#[cfg(feature = "sd-sprites")]
+impl TimeSource for NoClock {
+    fn get_timestamp(&self) -> Timestamp {
+        Timestamp {
+            year_since_1970:    0,
+            zero_indexed_month: 0,
+            zero_indexed_day:   0,
+            hours:              0,
+            minutes:            0,
+            seconds:            0,
+        }
+    }
 }
 
-struct TankIterator<'a> {
-    tank:     &'a FishTank<'a>,
-    position: Point,
+// Reads FISH.RAW from the root directory of the SD card's first
+// partition into buf, and returns its length.
+#[cfg(feature = "sd-sprites")]
+fn load_sd_sprites<S, CS>(spi: S, cs: CS, buf: &mut [u8])
+                          -> Result<usize, SdError>
+where S: embedded_hal::spi::FullDuplex<u8>,
+      S::Error: core::fmt::Debug,
+      CS: embedded_hal::digital::v2::OutputPin,
+{
+    let mut sd = Controller::new(SdMmcSpi::new(spi, cs), NoClock);
+    sd.device().init().map_err(|_| SdError::NoCard)?;
+    let mut volume = sd.get_volume(VolumeIdx(0))
+        .map_err(|_| SdError::NoCard)?;
+    let root = sd.open_root_dir(&volume).map_err(|_| SdError::NoCard)?;
+    let file = sd.open_file_in_dir(&mut volume, &root, "FISH.RAW",
+                                   Mode::ReadOnly);
+    let result = match file {
+        Ok(mut file) => {
+            let len: usize = file.length().try_into().unwrap();
+            let read = if len > buf.len() {
+                Err(SdError::TooBig)
+            } else {
+                sd.read(&volume, &mut file, &mut buf[..len])
+                    .map_err(|_| SdError::NoFile)
+            };
+            sd.close_file(&volume, file).ok();
+            read
+        }
+        Err(_) => Err(SdError::NoFile),
+    };
+    sd.close_dir(&volume, root);
+    result
 }
 
-fn cvt(u: u32) -> i32 {
-    u.try_into().unwrap()
+// Seed used instead of hardware entropy when built with the "fixed-seed"
+// feature, so that every run of the tank is the same.
+#[cfg(feature = "fixed-seed")]
+const FIXED_SEED: u64 = 0x1badd00d8badf00d;
+
+// Number of ADC samples mixed together by hardware_seed().
+#[cfg(not(feature = "fixed-seed"))]
+const SEED_SAMPLES: u32 = 64;
+
+// Gathers a random seed from the noise on an unconnected ADC pin.
+// Only the bottom four bits of each sample are noisy enough to be worth
+// keeping.  They are xored into the seed, rotating by four bits each
+// time, so the 64 samples cover each bit of the 64-bit seed four times.
+#[cfg(not(feature = "fixed-seed"))]
+fn hardware_seed<P>(adc: &mut Adc<pac::ADC0>, pin: &mut P) -> u64
+where Adc<pac::ADC0>: OneShot<Adc<pac::ADC0>, u16, P>,
+{
+    let mut seed: u64 = 0;
+    for _ in 0..SEED_SAMPLES {
+        let sample: u16 = adc.read(pin).unwrap_or(0);
+        seed = seed.rotate_left(4) ^ (sample & 0xf) as u64;
+    }
+    seed
 }
 
-fn rgb565(packed: u16) -> Rgb565 {
-    Rgb565::from(RawU16::new(packed))
+// Color of the empty tank, which fills with water when the board starts
+// up, with the "intro" feature.
+#[cfg(feature = "intro")]
+const INTRO_COLOR: u16 = 0;     // black
+
+// The GD32VF103's 96-bit unique device ID is at this address.
+#[cfg(not(feature = "fixed-seed"))]
+const DEVICE_ID: *const u32 = 0x1fff_f7e8 as *const u32;
+
+// The stream for the random number generator, which comes from the
+// device ID, so that two boards are different even if the noise gives
+// them the same seed.
+#[cfg(not(feature = "fixed-seed"))]
+fn device_stream() -> u64 {
+    // Safe because the device ID is always there, and never changes.
+    let id = unsafe {
+        [DEVICE_ID.read_volatile(),
+         DEVICE_ID.add(1).read_volatile(),
+         DEVICE_ID.add(2).read_volatile()]
+    };
+    (u64::from(id[0]) | u64::from(id[1]) << 32) ^ u64::from(id[2])
 }
 
-impl Sprite<'_> {
-    fn get_point(&self, pt: &Point, animation: u8) -> PointValue {
-        let x = pt.x - FUDGE_FACTOR;
-        let y = pt.y - FUDGE_FACTOR;
-        if x < 0 || y < 0 ||
-            x >= cvt(self.size.width) ||
-            y >= cvt(self.size.height) {
-            PointValue::Transparent
-        } else {
-            let x: usize = x.try_into().unwrap();
-            let y: usize = y.try_into().unwrap();
-            let width: usize = self.size.width.try_into().unwrap();
-            let idx: usize = x + y * width;
-            let frame_no: usize = animation.into();
-            let frame: &[u16] = self.frames[frame_no];
-            let c = frame[idx];
-            if c == TRANSPARENT {
-                PointValue::Transparent
-            } else {
-                PointValue::Opaque(c)
-            }
-        }
+// The main loop waits so that it runs no faster than this many times a
+// second, so that the fish swim at the same speed no matter how long
+// they take to draw.
+const TARGET_FPS: u32 = 20;
+
+// With "low-power", once nothing on the screen has changed for this
+// many frames in a row, or as soon as the tank is paused, the main loop
+// sleeps until the next frame instead of spinning.
+#[cfg(feature = "low-power")]
+const IDLE_FRAMES: u32 = 2 * TARGET_FPS;
+
+// Size of the Longan Nano's LCD, in pixels.
+const LCD_WIDTH: usize = 160;
+const LCD_HEIGHT: usize = 80;
+
+// Draws each row with a single window, instead of one per pixel.
+struct LcdRows<'a>(&'a mut lcd::Lcd);
+
+impl LineTarget for LcdRows<'_> {
+    type Error = ();
+
+    fn draw_row(&mut self,
+                start: Point,
+                pixels: &[Rgb565]) -> Result<(), ()> {
+        let x: u16 = start.x.try_into().unwrap();
+        let y: u16 = start.y.try_into().unwrap();
+        let len: u16 = pixels.len().try_into().unwrap();
+        let colors = pixels.iter().map(|&c| RawU16::from(c).into_inner());
+        self.0.set_pixels(x, y, x + len - 1, y, colors)
     }
+}
 
-    fn make_sprite(sprite_num: usize, sprite_data: &[u16]) -> Sprite {
-        let header_index = 4 * sprite_num;
-        let width_height = sprite_data[header_index];
-        let width = width_height >> 8;
-        let height = width_height & 0xff;
-        let num_words = width * height;
-
-        let mut sprite = Sprite {
-            size: Size::new(width.into(), height.into()),
-            frames: [&[]; NUM_FRAMES],
-        };
-
-        for frame in 0..3 {
-            let frame_index = sprite_data[header_index + frame + 1];
-            sprite.frames[frame] =
-                &sprite_data[frame_index.into()..(frame_index+num_words).into()];
-        }
+// Number of frames in a row that a button has to read the same before
+// we believe it changed state.
+const DEBOUNCE_FRAMES: u8 = 3;
 
-        sprite
-    }
+// A push button which connects a pin to ground.
+struct Button<P> {
+    pin:     P,
+    pressed: bool,
+    // Number of frames in a row the pin has disagreed with pressed.
+    count:   u8,
 }
 
-impl Fish<'_> {
-    fn get_point(&self, pt: &Point) -> PointValue {
-        if pt.x < self.upper_left.x ||
-            pt.y < self.upper_left.y ||
-            pt.x >= self.upper_left.x + cvt(self.size.width) ||
-            pt.y >= self.upper_left.y + cvt(self.size.height) {
-            PointValue::OutOfRange
-        } else {
-            let mut x = pt.x - self.upper_left.x;
-            let y = pt.y - self.upper_left.y;
-            if self.direction == Dir::Left {
-                x = cvt(self.size.width) - (x + 1);
-            }
-            self.fish_type.get_point(&Point::new(x, y),
-                                     self.animation / ANIMATION_SPEED)
+impl<P: InputPin> Button<P> {
+    fn new(pin: P) -> Button<P> {
+        Button {
+            pin,
+            pressed: false,
+            count:   0,
         }
     }
 
-    fn on_screen(&self, screen: &Size) -> bool {
-        self.upper_left.y <= cvt(screen.height) &&
-            self.upper_left.y + cvt(self.size.height) >= 0 &&
-            self.upper_left.x <= cvt(screen.width) &&
-            self.upper_left.x + cvt(self.size.width) >= 0
-    }
-
-    fn randomize<T: Rng>(&mut self, screen: &Size, rng: &mut T) {
-        let lo: u8 = 0;
-        let hi: u8 = NUM_FRAMES.try_into().unwrap();
-        self.animation = rng.gen_range(lo, hi * ANIMATION_SPEED);
-        if rng.gen() {
-            self.direction = Dir::Left;
-            self.upper_left.x = cvt(screen.width);
+    // Call once per frame.  Returns true once for each press.
+    fn poll(&mut self) -> bool {
+        let down = self.pin.is_low().unwrap_or(false);
+        if down == self.pressed {
+            self.count = 0;
+            false
         } else {
-            self.direction = Dir::Right;
-            self.upper_left.x = -cvt(self.size.width);
-        }
-        self.upper_left.y =
-            cvt(rng.gen_range(0, screen.height - self.size.height));
-    }
-
-    fn randomize_x<T: Rng>(&mut self, screen: &Size, rng: &mut T) {
-        self.upper_left.x =
-            cvt(rng.gen_range(0, screen.width - self.size.width));
-    }
-
-    fn swim<T: Rng>(&mut self, screen: &Size, rng: &mut T) {
-        if rng.gen_ratio(3, 4) {
-            self.upper_left.x += match self.direction {
-                Dir::Left => -1,
-                Dir::Right => 1,
+            self.count += 1;
+            if self.count >= DEBOUNCE_FRAMES {
+                self.pressed = down;
+                self.count = 0;
+                down
+            } else {
+                false
             }
         }
-
-        if rng.gen_ratio(1, 8) {
-            self.upper_left.y += rng.gen_range(-1, 2);
-        }
-
-        self.animation += 1;
-        let num_frames: u8 = NUM_FRAMES.try_into().unwrap();
-        if self.animation >= num_frames * ANIMATION_SPEED {
-            self.animation = 0;
-        }
-
-        if self.on_screen(screen) == false {
-            self.randomize(screen, rng);
-        }
-    }
-
-    fn new<'a>(sprite: Sprite<'a>) -> Fish<'a> {
-        let ff2: u32 = (FUDGE_FACTOR * 2).try_into().unwrap();
-        Fish {
-            fish_type:  sprite,
-            upper_left: Point::new(0, 0),
-            size:       Size::new(sprite.size.width + ff2,
-                                  sprite.size.height + ff2),
-            direction:  Dir::Right,
-            animation:  0,
-        }
     }
 }
 
-impl FishTank<'_> {
-    fn new(screen_size: Size, seed: u64) -> FishTank<'static> {
-        let sprite_data = SPRITE_DATA.as_slice_of::<u16>().unwrap();
-        let dummy_sprite = Sprite::make_sprite(0, sprite_data);
-        let mut tank = FishTank {
-            fish:    [Fish::new(dummy_sprite); NUM_FISH],
-            size:    screen_size,
-            rng:     Pcg32::new(seed, 0xdefacedbadfacade),
-        };
+// Commands which can be typed over the serial port (USART0, 115200 baud):
+//
+//   +        add a fish
+//   -        remove a fish
+//   p        pause, or carry on if already paused
+//   r        start over with a new seed
+//   s        give the fish new species
+//   f X Y    drop food at X pixels from the left and Y from the top
+//   d        send a screenshot, as a PPM image
+//
+// The single-character commands take effect as soon as they are typed
+// at the start of a line, without waiting for Enter.  Lines are ended
+// by CR or LF, and blank lines are ignored.
+enum Command {
+    AddFish,
+    RemoveFish,
+    Pause,
+    Reset,
+    Reshuffle,
+    DropFood(Point),
+    Screenshot,
+    Unknown,
+}
 
-        for i in 0..NUM_FISH {
-            let sprite = Sprite::make_sprite(i % NUM_SPRITES, sprite_data);
-            tank.fish[i] = Fish::new(sprite);
-            tank.fish[i].randomize  (&tank.size, &mut tank.rng);
-            tank.fish[i].randomize_x(&tank.size, &mut tank.rng);
-        }
+// Longest line that can be typed, not counting the line ending.
+const COMMAND_LEN: usize = 16;
 
-        tank
-    }
+// Collects characters from the serial port into commands.
+struct CommandReader {
+    line:     [u8; COMMAND_LEN],
+    len:      usize,
+    // Set if the line got too long, in which case it is thrown away.
+    overflow: bool,
+}
 
-    fn swim(&mut self) {
-        for i in 0..NUM_FISH {
-            self.fish[i].swim(&self.size, &mut self.rng);
+impl CommandReader {
+    fn new() -> CommandReader {
+        CommandReader {
+            line:     [0; COMMAND_LEN],
+            len:      0,
+            overflow: false,
         }
     }
 
-    fn get_point(&self, pt: &Point) -> PointValue {
-        let mut ret = PointValue::OutOfRange;
-        for i in 0..NUM_FISH {
-            match self.fish[i].get_point(pt) {
-                PointValue::Opaque(c)   => return PointValue::Opaque(c),
-                PointValue::Transparent => ret = PointValue::Transparent,
-                PointValue::OutOfRange  => (),
+    // Call with each character received.  Returns a command once one
+    // has been typed.
+    fn push(&mut self, c: u8) -> Option<Command> {
+        let at_start = self.len == 0 && !self.overflow;
+        match c {
+            b'+' if at_start => Some(Command::AddFish),
+            b'-' if at_start => Some(Command::RemoveFish),
+            b'p' if at_start => Some(Command::Pause),
+            b'r' if at_start => Some(Command::Reset),
+            b's' if at_start => Some(Command::Reshuffle),
+            b'd' if at_start => Some(Command::Screenshot),
+            b'\r' | b'\n' if at_start => None,
+            b'\r' | b'\n' => {
+                let command = if self.overflow {
+                    Command::Unknown
+                } else {
+                    parse_command(&self.line[..self.len])
+                };
+                self.len = 0;
+                self.overflow = false;
+                Some(command)
+            }
+            _ if self.len < COMMAND_LEN => {
+                self.line[self.len] = c;
+                self.len += 1;
+                None
+            }
+            _ => {
+                self.overflow = true;
+                None
             }
         }
-
-        ret
     }
 }
 
-impl TankIterator<'_> {
-    fn new<'a>(fish_tank: &'a FishTank<'a>) -> TankIterator<'a> {
-        TankIterator {
-            tank:     fish_tank,
-            position: Point::new(0, 0),
-        }
+// Parses a whole line, which so far can only be the "f" command.
+fn parse_command(line: &[u8]) -> Command {
+    let mut words = core::str::from_utf8(line).unwrap_or("")
+        .split_whitespace();
+    match (words.next(), words.next(), words.next(), words.next()) {
+        (Some("f"), Some(x), Some(y), None) => match (x.parse(), y.parse()) {
+            (Ok(x), Ok(y)) => Command::DropFood(Point::new(x, y)),
+            _ => Command::Unknown,
+        },
+        _ => Command::Unknown,
     }
+}
 
-    fn some_color(&self, c: u16) -> Option<Pixel<Rgb565>> {
-        Some(Pixel(self.position, rgb565(c)))
+// Sends `pixels`, rows of `width` RGB565 pixels, as a binary PPM image,
+// as laid out by ppm_bytes().  For the Longan Nano's LCD, the header is
+// "P6\n160 80\n255\n".  At 115200 baud, the whole screen takes about 7
+// seconds.
+#[cfg(feature = "shadow-framebuffer")]
+fn send_ppm<W>(tx: &mut W, pixels: &[u16], width: usize)
+where W: embedded_hal::serial::Write<u8>,
+{
+    for byte in ppm_bytes(pixels, width) {
+        nb::block!(embedded_hal::serial::Write::write(tx, byte)).ok();
     }
 }
 
-impl Iterator for TankIterator<'_> {
-    type Item = Pixel<Rgb565>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            if self.position.y >= cvt(self.tank.size.height) {
-                return None;
-            } else {
-                let pv = self.tank.get_point(&self.position);
-                let ret = match pv {
-                    PointValue::OutOfRange    => None,
-                    PointValue::Transparent   => self.some_color(BACKGROUND),
-                    PointValue::Opaque(color) => self.some_color(color),
-                };
-
-                self.position.x += 1;
-                if self.position.x >= cvt(self.tank.size.width) {
-                    self.position.x = 0;
-                    self.position.y += 1;
-                }
-
-                if let Some(_) = ret {
-                    return ret;
-                }
+// Sends the tank as a binary PPM image, just like send_ppm(), but works
+// it out a row at a time in `line_buf`, for builds without a copy of
+// the whole screen.
+#[cfg(not(feature = "shadow-framebuffer"))]
+fn send_ppm_rows<W>(tx: &mut W,
+                    tank: &DefaultFishTank<'_>,
+                    line_buf: &mut [Rgb565])
+where W: embedded_hal::serial::Write<u8>,
+{
+    let mut send = |byte| {
+        nb::block!(embedded_hal::serial::Write::write(tx, byte)).ok();
+    };
+    for byte in ppm_header(LCD_WIDTH, LCD_HEIGHT) {
+        send(byte);
+    }
+    for y in 0..LCD_HEIGHT {
+        tank.render_row(y.try_into().unwrap(), line_buf).unwrap();
+        for &c in &line_buf[..LCD_WIDTH] {
+            for byte in rgb888(RawU16::from(c).into_inner()) {
+                send(byte);
             }
         }
     }
 }
 
+// Timestamps for log messages are in CPU cycles.
+#[cfg(feature = "defmt-log")]
+#[defmt::timestamp]
+fn timestamp() -> u64 {
+    riscv::register::mcycle::read64()
+}
+
 // adapted from
 // https://github.com/riscv-rust/longan-nano/blob/master/examples/ferris.rs
 
@@ -319,13 +416,230 @@ fn main() -> ! {
     let lcd_pins = lcd_pins!(gpioa, gpiob);
     let mut lcd = lcd::configure(dp.SPI0, lcd_pins, &mut afio, &mut rcu);
 
-    // Clear screen
-    lcd.clear(rgb565(BACKGROUND)).unwrap();
+    // The Longan Nano's own buttons are RESET and BOOT0, neither of which
+    // can be read by the program, so these are external buttons.
+    let mut add_button = Button::new(gpioa.pa8.into_pull_up_input());
+    let mut remove_button = Button::new(gpiob.pb8.into_pull_up_input());
+
+    #[cfg(feature = "fixed-seed")]
+    let mut seed = FIXED_SEED;
+    #[cfg(feature = "fixed-seed")]
+    let stream = DEFAULT_STREAM;
+    #[cfg(not(feature = "fixed-seed"))]
+    let stream = device_stream();
+    // Nothing is connected to PA3, so it picks up noise.  The ADC is kept
+    // around for new seeds when the tank is reset.
+    #[cfg(not(feature = "fixed-seed"))]
+    let mut adc = Adc::adc0(dp.ADC0, &mut rcu);
+    #[cfg(not(feature = "fixed-seed"))]
+    let mut noise_pin = gpioa.pa3.into_analog();
+    #[cfg(not(feature = "fixed-seed"))]
+    let mut seed = hardware_seed(&mut adc, &mut noise_pin);
+
+    // Warnings about dropped frames go out on USART0, and commands come
+    // in on it.
+    let tx = gpioa.pa9.into_alternate_push_pull();
+    let rx = gpioa.pa10.into_floating_input();
+    let config = Config::default().baudrate(115_200.bps());
+    let serial = Serial::new(dp.USART0, (tx, rx), config, &mut afio, &mut rcu);
+    let (mut serial_tx, mut serial_rx) = serial.split();
+    let mut commands = CommandReader::new();
+
+    // Fish from FISH.RAW on the SD card replace the built-in ones.  The
+    // card is on SPI1, which is kept slow enough for the card to start
+    // up.
+    #[cfg(feature = "sd-sprites")]
+    let mut sd_buf = Aligned([0; SD_SPRITE_MAX]);
+    #[cfg(feature = "sd-sprites")]
+    let sprite_bytes: &[u8] = {
+        let sck = gpiob.pb13.into_alternate_push_pull();
+        let miso = gpiob.pb14.into_floating_input();
+        let mosi = gpiob.pb15.into_alternate_push_pull();
+        let cs = gpiob.pb12.into_push_pull_output();
+        let spi = Spi::spi1(dp.SPI1, (sck, miso, mosi), MODE_0, 400.khz(),
+                            &mut rcu);
+        match load_sd_sprites(spi, cs, &mut sd_buf.0) {
+            Ok(len) => &sd_buf.0[..len],
+            Err(e) => {
+                writeln!(serial_tx, "{}, using the built-in fish",
+                         e.message()).ok();
+                &SPRITE_DATA.0
+            }
+        }
+    };
+    #[cfg(not(feature = "sd-sprites"))]
+    let sprite_bytes: &[u8] = &SPRITE_DATA.0;
+
+    // If the sprite data is broken, turn the screen red before giving
+    // up, so that it's clear something is wrong.
+    let builder = FishTankBuilder::new(sprite_bytes).seed(seed)
+        .stream(stream);
+    #[cfg(feature = "intro")]
+    let builder = builder.intro(INTRO_COLOR);
+    let fish_tank = builder.build(lcd.size());
+    if fish_tank.is_err() {
+        lcd.clear(Rgb565::RED).unwrap();
+    }
+    let mut fish_tank = fish_tank.unwrap();
 
-    let mut fish_tank = FishTank::new(lcd.size(), 0x1badd00d8badf00d);
+    // Clear screen
+    lcd.clear(rgb565(fish_tank.clear_color())).unwrap();
+
+    let mut line_buf = [rgb565(0); LCD_WIDTH];
+
+    // Changed pixels are found by comparing against a copy of the
+    // screen, which starts off cleared just like the screen.
+    #[cfg(feature = "shadow-framebuffer")]
+    let mut shadow = [fish_tank.clear_color(); LCD_WIDTH * LCD_HEIGHT];
+    // Changed rows are found by comparing hashes of them.  Hashes of
+    // zero make the first frame draw every row.
+    #[cfg(not(feature = "shadow-framebuffer"))]
+    let mut row_hashes = [0; LCD_HEIGHT];
+
+    // The timer counts down one frame at a time, whether or not we are
+    // waiting for it.
+    let mut frame_timer = Timer::timer1(dp.TIMER1, TARGET_FPS.hz(), &mut rcu);
+    // The timer's interrupt is what wakes the core up from WFI.
+    // Interrupts are never enabled globally, so no handler runs, and the
+    // core just carries on after the WFI.
+    #[cfg(feature = "low-power")]
+    {
+        frame_timer.listen(Event::Update);
+        pac::ECLIC::reset();
+        pac::ECLIC::set_threshold_level(Level::L0);
+        pac::ECLIC::setup(pac::Interrupt::TIMER1, TriggerType::Level,
+                          Level::L1, Priority::P1);
+        unsafe { pac::ECLIC::unmask(pac::Interrupt::TIMER1) };
+    }
+    #[cfg(feature = "low-power")]
+    let mut idle_frames: u32 = 0;
+    let mut frames: u32 = 0;
+    let mut dropped: u32 = 0;
+    let mut changed = true;
+    // Set when a screenshot has been asked for, which is sent once the
+    // frame has been drawn.
+    let mut screenshot = false;
 
     loop {
-        lcd.draw_iter(TankIterator::new(&fish_tank)).unwrap();
-        fish_tank.swim();
+        if add_button.poll() && !fish_tank.add_fish() {
+            writeln!(serial_tx, "the tank is full").ok();
+        }
+        if remove_button.poll() && !fish_tank.remove_fish() {
+            writeln!(serial_tx, "there are no fish to remove").ok();
+        }
+
+        while let Ok(c) = serial_rx.read() {
+            match commands.push(c) {
+                Some(Command::AddFish) => {
+                    if !fish_tank.add_fish() {
+                        writeln!(serial_tx, "the tank is full").ok();
+                    }
+                }
+                Some(Command::RemoveFish) => {
+                    if !fish_tank.remove_fish() {
+                        writeln!(serial_tx, "there are no fish to remove").ok();
+                    }
+                }
+                Some(Command::Pause) =>
+                    fish_tank.set_paused(!fish_tank.paused()),
+                Some(Command::Reset) => {
+                    // With a fixed seed, resets still go the same way
+                    // every run.
+                    #[cfg(feature = "fixed-seed")]
+                    {
+                        seed = seed.wrapping_add(1);
+                    }
+                    #[cfg(not(feature = "fixed-seed"))]
+                    {
+                        seed = hardware_seed(&mut adc, &mut noise_pin);
+                    }
+                    fish_tank.reset_with_stream(seed, stream);
+                    // Every pixel is checked when drawing, so there's no
+                    // need to clear the screen first.
+                    changed = true;
+                }
+                Some(Command::Reshuffle) => fish_tank.reshuffle_species(),
+                Some(Command::DropFood(at)) => {
+                    if !fish_tank.drop_food(at) {
+                        writeln!(serial_tx, "can't drop food there").ok();
+                    }
+                }
+                Some(Command::Screenshot) => screenshot = true,
+                Some(Command::Unknown) => {
+                    writeln!(serial_tx, "unknown command").ok();
+                }
+                None => (),
+            }
+        }
+
+        #[cfg(feature = "defmt-log")]
+        let start = riscv::register::mcycle::read();
+
+        // Nothing has been drawn yet the first time around.
+        if changed {
+            let mut rows = LcdRows(&mut lcd);
+            #[cfg(feature = "shadow-framebuffer")]
+            fish_tank.render_shadowed(&mut rows, &mut shadow, &mut line_buf)
+                .unwrap();
+            #[cfg(not(feature = "shadow-framebuffer"))]
+            fish_tank.render_hashed(&mut rows, &mut row_hashes, &mut line_buf)
+                .unwrap();
+        }
+        // The shadow framebuffer now matches the tank, so it can be sent
+        // as it is.  Without it, the screen is worked out again, a row
+        // at a time.
+        if screenshot {
+            #[cfg(feature = "shadow-framebuffer")]
+            send_ppm(&mut serial_tx, &shadow, LCD_WIDTH);
+            #[cfg(not(feature = "shadow-framebuffer"))]
+            send_ppm_rows(&mut serial_tx, &fish_tank, &mut line_buf);
+            screenshot = false;
+        }
+        // The fish only start swimming once the tank has filled up.
+        changed = fish_tank.intro_frame() || fish_tank.step();
+
+        #[cfg(feature = "low-power")]
+        let idle = {
+            idle_frames = if changed {
+                0
+            } else {
+                idle_frames.saturating_add(1)
+            };
+            fish_tank.paused() || idle_frames >= IDLE_FRAMES
+        };
+
+        #[cfg(feature = "defmt-log")]
+        defmt::debug!("{} fish, frame took {} cycles", fish_tank.num_fish(),
+                      riscv::register::mcycle::read().wrapping_sub(start));
+
+        // If the timer already ran out, this frame took too long.
+        let frame_budget_exceeded = match frame_timer.wait() {
+            Ok(()) => true,
+            Err(_) => {
+                // Nothing is drawn or changed while the core sleeps, so
+                // the tank carries on from exactly where it was.
+                #[cfg(feature = "low-power")]
+                {
+                    if idle {
+                        unsafe { riscv::asm::wfi() };
+                    }
+                }
+                nb::block!(frame_timer.wait()).unwrap();
+                false
+            }
+        };
+
+        if frame_budget_exceeded {
+            dropped += 1;
+        }
+        frames += 1;
+        if frames == TARGET_FPS {
+            if dropped > 0 {
+                writeln!(serial_tx, "warning: {} of the last {} frames \
+                                     took too long", dropped, frames).ok();
+            }
+            frames = 0;
+            dropped = 0;
+        }
     }
 }